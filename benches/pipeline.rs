@@ -0,0 +1,123 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use infonode::auth::Entitlements;
+use infonode::book::Exchange;
+use infonode::config::Config;
+use infonode::mock::{binance_depth, MockExchange, Step};
+use infonode::pipeline::{Pipelines, SummaryReceiver};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::Status;
+
+// summaries each client waits for
+const UPDATES: usize = 5_000;
+// binance frames from the mock, about 1000 a second
+const PACE: Duration = Duration::from_millis(1);
+
+// latency from a frame received by the connector to a client of the summary
+// stream, for each number of clients given (1, 100 and 500 by default):
+//
+//   cargo bench --bench pipeline -- 1 10
+#[tokio::main]
+async fn main() {
+    let counts: Vec<usize> = std::env::args()
+        .skip(1)
+        .filter_map(|arg| arg.parse().ok())
+        .collect();
+    let counts = match counts.is_empty() {
+        true => vec![1, 100, 500],
+        false => counts,
+    };
+    for clients in counts {
+        let mut latencies = run(clients).await;
+        latencies.sort_unstable();
+        let p =
+            |q: f64| latencies[((q * latencies.len() as f64) as usize).min(latencies.len() - 1)];
+        println!(
+            "{:4} clients {:8} summaries p50 {:5}us p90 {:5}us p99 {:6}us p99.9 {:6}us max {:7}us",
+            clients,
+            latencies.len(),
+            p(0.5),
+            p(0.9),
+            p(0.99),
+            p(0.999),
+            latencies[latencies.len() - 1]
+        );
+    }
+}
+
+async fn run(clients: usize) -> Vec<u64> {
+    let mock = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (8, 8),
+        vec![vec![Step::Repeat(
+            binance_depth(&[("0.25", "1"), ("0.125", "2")], &[("0.5", "2")]),
+            PACE,
+        )]],
+    );
+    let mut config = Config {
+        pairs: vec!["ethbtc".to_string()],
+        summary_latency: true,
+        ..Config::default()
+    };
+    config.exchanges.bitstamp.enabled = false;
+    let endpoints = mock.endpoints();
+    let binance = config.exchanges.get_mut(&Exchange::Binance);
+    binance.rest = Some(endpoints.rest);
+    binance.websocket = Some(endpoints.websocket);
+
+    let mut pipelines = Pipelines::start(config).unwrap();
+    let registry = pipelines.registry();
+    let streams: Vec<_> = (0..clients)
+        .map(|_| {
+            let (_, rx) = registry
+                .read()
+                .unwrap()
+                .summaries("ethbtc", &Entitlements::default())
+                .unwrap();
+            tokio::spawn(receive(rx))
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(clients * UPDATES);
+    for stream in streams {
+        latencies.extend(stream.await.unwrap());
+    }
+    pipelines.close(Status::unavailable("done")).await;
+    latencies
+}
+
+// the connector got the frame parse, queue and book before the send
+async fn receive(mut rx: SummaryReceiver) -> Vec<u64> {
+    let mut latencies = Vec::with_capacity(UPDATES);
+    while latencies.len() < UPDATES {
+        let Some(Ok(summary)) = rx.recv().await else {
+            break;
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        if let Some(latency) = summary.latency {
+            let received = latency.sent_us - latency.parse_us - latency.queue_us - latency.book_us;
+            latencies.push((now.as_micros() as u64).saturating_sub(received));
+        }
+    }
+    latencies
+}
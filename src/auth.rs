@@ -0,0 +1,348 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::config::{AuthConfig, KeyConfig};
+use crate::orderbook::{ExchangeStatus, Summary};
+use log::debug;
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time::Instant;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+// what a client may see, everything by default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entitlements {
+    pub name: String,
+    pub pairs: Vec<String>,
+    pub exchanges: Vec<String>,
+    pub max_depth: Option<usize>,
+    // summaries per second
+    pub max_rate: Option<f64>,
+}
+
+impl Entitlements {
+    fn new(key: &KeyConfig) -> Entitlements {
+        Entitlements {
+            name: key.name.clone(),
+            pairs: key.pairs.clone(),
+            exchanges: key.exchanges.iter().map(|e| e.to_string()).collect(),
+            max_depth: key.max_depth,
+            max_rate: key.max_rate,
+        }
+    }
+
+    pub fn allows_pair(&self, pair: &str) -> bool {
+        self.pairs.is_empty() || self.pairs.iter().any(|p| p == pair)
+    }
+
+    pub fn allows_exchange(&self, exchange: &str) -> bool {
+        self.exchanges.is_empty() || self.exchanges.iter().any(|e| e == exchange)
+    }
+
+    pub fn allows_status(&self, status: &ExchangeStatus) -> bool {
+        self.allows_pair(&status.pair) && self.allows_exchange(&status.exchange)
+    }
+
+    // a client asking for fewer levels than it may see
+    pub fn limit_depth(&mut self, depth: usize) {
+        self.max_depth = Some(self.max_depth.map_or(depth, |max| cmp::min(max, depth)));
+    }
+
+    pub fn restricts_summaries(&self) -> bool {
+        !self.exchanges.is_empty() || self.max_depth.is_some() || self.max_rate.is_some()
+    }
+
+    // drop the levels of other exchanges and cut the book to max_depth
+    pub fn filter_summary(&self, mut summary: Summary) -> Summary {
+        if !self.exchanges.is_empty() {
+            summary.bids.retain(|l| self.allows_exchange(&l.exchange));
+            summary.asks.retain(|l| self.allows_exchange(&l.exchange));
+            summary
+                .exchanges
+                .retain(|s| self.allows_exchange(&s.exchange));
+            if let Some(latency) = &summary.latency {
+                if !self.allows_exchange(&latency.exchange) {
+                    summary.latency = None;
+                }
+            }
+            summary.spread = match (summary.asks.first(), summary.bids.first()) {
+                (Some(ask), Some(bid)) => ask.price - bid.price,
+                (None, Some(bid)) => -bid.price,
+                (Some(ask), None) => ask.price,
+                (None, None) => 0.0,
+            };
+        }
+        if let Some(depth) = self.max_depth {
+            summary.bids.truncate(depth);
+            summary.asks.truncate(depth);
+        }
+        summary
+    }
+}
+
+// checks the api key of every call and attaches its entitlements
+#[derive(Clone, Default)]
+pub struct Authenticator {
+    keys: Arc<RwLock<HashMap<String, Entitlements>>>,
+}
+
+impl Authenticator {
+    pub fn new(auth: &AuthConfig) -> Authenticator {
+        let authenticator = Authenticator::default();
+        authenticator.reload(auth);
+        authenticator
+    }
+
+    // open streams keep the entitlements they started with
+    pub fn reload(&self, auth: &AuthConfig) {
+        *self.keys.write().unwrap() = auth
+            .keys
+            .iter()
+            .map(|key| (key.key.clone(), Entitlements::new(key)))
+            .collect();
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn entitlements(&self, token: Option<&str>) -> Result<Entitlements, Status> {
+        let keys = self.keys.read().unwrap();
+        if keys.is_empty() {
+            return Ok(Entitlements::default());
+        }
+        let entitlements = token
+            .and_then(|token| keys.get(token))
+            .ok_or_else(|| Status::unauthenticated("missing or unknown api key"))?;
+        debug!("authenticated {}", entitlements.name);
+        Ok(entitlements.clone())
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let entitlements = self.entitlements(token(request.metadata()))?;
+        request.extensions_mut().insert(entitlements);
+        Ok(request)
+    }
+}
+
+// `authorization: Bearer <key>` or `x-api-key: <key>`
+fn token(metadata: &MetadataMap) -> Option<&str> {
+    bearer(
+        metadata.get("authorization").and_then(|v| v.to_str().ok()),
+        metadata.get("x-api-key").and_then(|v| v.to_str().ok()),
+    )
+}
+
+pub fn bearer<'a>(authorization: Option<&'a str>, api_key: Option<&'a str>) -> Option<&'a str> {
+    match authorization {
+        Some(value) => value.strip_prefix("Bearer "),
+        None => api_key,
+    }
+}
+
+// filter a stream and, with a max_rate, conflate it to at most max_rate
+// messages per second; without one every message kept by the filter goes
+// through
+pub fn restrict<T, F>(
+    mut rx: Receiver<Result<T, Status>>,
+    max_rate: Option<f64>,
+    filter: F,
+) -> Receiver<Result<T, Status>>
+where
+    T: Send + 'static,
+    F: Fn(T) -> Option<T> + Send + 'static,
+{
+    let (tx, restricted) = mpsc::channel(100);
+    let interval = max_rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+    tokio::spawn(async move {
+        let mut pending = None;
+        let mut next_send = Instant::now();
+        loop {
+            // a due message goes before the next one is read, or a busy
+            // stream would hold it back
+            tokio::select! {
+                biased;
+                _ = tx.closed() => return,
+                _ = tokio::time::sleep_until(next_send), if pending.is_some() => {
+                    if tx.send(Ok(pending.take().unwrap())).await.is_err() {
+                        return;
+                    }
+                    next_send = Instant::now() + interval.unwrap_or_default();
+                }
+                msg = rx.recv() => match msg {
+                    Some(Ok(msg)) => match (filter(msg), interval) {
+                        (Some(msg), None) => {
+                            if tx.send(Ok(msg)).await.is_err() {
+                                return;
+                            }
+                        }
+                        (Some(msg), Some(_)) => pending = Some(msg),
+                        (None, _) => {}
+                    },
+                    // the stream ends, last message first
+                    end => {
+                        if let Some(msg) = pending.take() {
+                            let _ = tx.send(Ok(msg)).await;
+                        }
+                        if let Some(Err(status)) = end {
+                            let _ = tx.send(Err(status)).await;
+                        }
+                        return;
+                    }
+                },
+            }
+        }
+    });
+    restricted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::Exchange;
+    use crate::orderbook::Level;
+
+    fn level(exchange: &str, price: f64) -> Level {
+        Level {
+            exchange: exchange.to_string(),
+            price,
+            amount: 1.0,
+        }
+    }
+
+    fn key(key: &str) -> KeyConfig {
+        KeyConfig {
+            name: "desk".to_string(),
+            key: key.to_string(),
+            pairs: vec!["ethbtc".to_string()],
+            exchanges: vec![Exchange::Binance],
+            max_depth: Some(1),
+            max_rate: None,
+        }
+    }
+
+    #[test]
+    fn test_filter_summary() {
+        let entitlements = Entitlements::new(&key("secret"));
+        assert!(entitlements.allows_pair("ethbtc"));
+        assert!(!entitlements.allows_pair("btcusdt"));
+
+        let summary = entitlements.filter_summary(Summary {
+            spread: 0.25,
+            bids: vec![
+                level("bitstamp", 0.75),
+                level("binance", 0.5),
+                level("binance", 0.25),
+            ],
+            asks: vec![level("bitstamp", 1.0), level("binance", 1.5)],
+            ..Summary::default()
+        });
+        assert_eq!(summary.bids, vec![level("binance", 0.5)]);
+        assert_eq!(summary.asks, vec![level("binance", 1.5)]);
+        assert_eq!(summary.spread, 1.0);
+    }
+
+    #[test]
+    fn test_interceptor() {
+        let mut authenticator = Authenticator::default();
+        // no key configured, no check
+        let request = authenticator.call(Request::new(())).unwrap();
+        assert_eq!(
+            request.extensions().get::<Entitlements>(),
+            Some(&Entitlements::default())
+        );
+
+        authenticator.reload(&AuthConfig {
+            keys: vec![key("secret")],
+        });
+        let status = authenticator.call(Request::new(())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        for (header, value) in [("authorization", "Bearer secret"), ("x-api-key", "secret")] {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert(header, value.parse().unwrap());
+            let request = authenticator.call(request).unwrap();
+            assert_eq!(
+                request.extensions().get::<Entitlements>().unwrap().name,
+                "desk"
+            );
+        }
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer other".parse().unwrap());
+        assert!(authenticator.call(request).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restrict_rate() {
+        let (tx, rx) = mpsc::channel(100);
+        let mut rx = restrict(rx, Some(10.0), |n: u32| n.is_multiple_of(2).then_some(n));
+        tx.send(Ok(0)).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().unwrap(), 0);
+        let start = Instant::now();
+        for n in 1..6 {
+            tx.send(Ok(n)).await.unwrap();
+        }
+        // conflated to the latest message kept by the filter
+        assert_eq!(rx.recv().await.unwrap().unwrap(), 4);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        tx.send(Ok(6)).await.unwrap();
+        tx.send(Err(Status::unavailable("closed"))).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().unwrap(), 6);
+        assert!(rx.recv().await.unwrap().is_err());
+        assert!(rx.recv().await.is_none());
+
+        // a stream dropped without a status still delivers its last message
+        let (tx, rx) = mpsc::channel(100);
+        let mut rx = restrict(rx, Some(10.0), |n: u32| Some(n));
+        tx.send(Ok(0)).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().unwrap(), 0);
+        tx.send(Ok(1)).await.unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().await.unwrap().unwrap(), 1);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_restrict_every_message() {
+        let (tx, rx) = mpsc::channel(100);
+        let mut rx = restrict(rx, None, |n: u32| n.is_multiple_of(2).then_some(n));
+        for n in 0..6 {
+            tx.send(Ok(n)).await.unwrap();
+        }
+        tx.send(Err(Status::unavailable("closed"))).await.unwrap();
+        // nothing conflated, only filtered
+        for n in [0, 2, 4] {
+            assert_eq!(rx.recv().await.unwrap().unwrap(), n);
+        }
+        assert!(rx.recv().await.unwrap().is_err());
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -22,87 +22,193 @@
  */
 use crate::book::Exchange;
 use crate::book::Update;
-use crossbeam_channel::Sender;
-use log::info;
-use tungstenite::connect;
+use crate::endpoints::Endpoints;
+use crate::ingress::Ingress;
+use crate::metrics::METRICS;
+use crate::orderbook::ConnectorState;
+use crate::recorder::Recorder;
+use crate::status::{Backoff, StatusReporter, StopSignal};
+use futures::StreamExt;
+use std::time::SystemTime;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
 use url::Url;
 
 pub struct BinanceClient {
     pair: String,
+    endpoints: Endpoints,
+    recorder: Option<Recorder>,
 }
 
 impl BinanceClient {
     pub fn new(pair: String) -> BinanceClient {
         BinanceClient {
             pair: pair.to_string(),
+            endpoints: Endpoints::new(&Exchange::Binance),
+            recorder: None,
         }
     }
 
-    pub async fn precisions(pair: String) -> (u64, u64) {
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
+
+    pub fn record_to(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub async fn precisions(rest_url: String, pair: String) -> Result<(u64, u64), String> {
         let precision_url = format!(
-            "{}{}",
-            "https://api.binance.com/api/v3/exchangeInfo?symbol=",
+            "{}{}{}",
+            rest_url,
+            "/api/v3/exchangeInfo?symbol=",
             pair.to_ascii_uppercase()
         );
 
         let body = reqwest::get(precision_url)
             .await
-            .unwrap()
+            .map_err(|e| e.to_string())?
             .text()
             .await
-            .unwrap();
+            .map_err(|e| e.to_string())?;
 
-        let parsed = json::parse(body.as_str()).unwrap();
+        let parsed = json::parse(body.as_str()).map_err(|e| e.to_string())?;
 
         if parsed.has_key("symbols") && parsed["symbols"].is_array() && parsed["symbols"].len() == 1
         {
-            return (
-                parsed["symbols"][0]["quotePrecision"].as_u64().unwrap(),
-                parsed["symbols"][0]["baseAssetPrecision"].as_u64().unwrap(),
+            if let (Some(p_prec), Some(a_prec)) = (
+                parsed["symbols"][0]["quotePrecision"].as_u64(),
+                parsed["symbols"][0]["baseAssetPrecision"].as_u64(),
+            ) {
+                return Ok((p_prec, a_prec));
+            }
+        }
+        Err("cannot get precisions from binance".to_string())
+    }
+
+    pub fn parse(frame: &str, p_prec: u64, a_prec: u64) -> Option<Update> {
+        let parsed = json::parse(frame).ok()?;
+        if !parsed.has_key("asks") && !parsed.has_key("bids") {
+            return None;
+        }
+        let mut orders = Update::new(Exchange::Binance, p_prec, a_prec);
+        if parsed.has_key("asks") && parsed["asks"].is_array() {
+            for i in 0..parsed["asks"].len() {
+                if parsed["asks"][i].len() == 2 {
+                    orders.add_ask(
+                        &parsed["asks"][i][0].to_string(),
+                        &parsed["asks"][i][1].to_string(),
+                    );
+                }
+            }
+        }
+        if parsed.has_key("bids") && parsed["bids"].is_array() {
+            for i in 0..parsed["bids"].len() {
+                if parsed["bids"][i].len() == 2 {
+                    orders.add_bid(
+                        &parsed["bids"][i][0].to_string(),
+                        &parsed["bids"][i][1].to_string(),
+                    );
+                }
+            }
+        }
+        Some(orders)
+    }
+
+    pub fn do_main_loop(
+        self,
+        tx: Ingress,
+        status: StatusReporter,
+        stop: StopSignal,
+    ) -> JoinHandle<()> {
+        tokio::spawn(self.run(tx, status, stop))
+    }
+
+    async fn run(mut self, tx: Ingress, status: StatusReporter, stop: StopSignal) {
+        let mut backoff = Backoff::new();
+        while !stop.is_stopped() {
+            status.report(ConnectorState::Connecting, &self.endpoints.websocket);
+            match self.session(&tx, &status, &stop, &mut backoff).await {
+                // stopped or the book is gone, nothing left to feed
+                Ok(()) => break,
+                Err(e) => status.report(ConnectorState::Failed, &e),
+            }
+            let delay = backoff.delay();
+            status.report(
+                ConnectorState::Reconnecting,
+                &format!("retry in {}s", delay.as_secs()),
             );
+            stop.sleep(delay).await;
         }
-        panic!("cannot get precisions from binance");
+        status.report(ConnectorState::Disconnected, "stopped");
     }
 
-    pub fn do_main_loop(&self, tx: Sender<Update>) {
-        let stream_url = format!(
-            "{}{}{}",
-            "wss://stream.binance.com:9443/ws/", self.pair, "@depth10@100ms"
+    async fn session(
+        &mut self,
+        tx: &Ingress,
+        status: &StatusReporter,
+        stop: &StopSignal,
+        backoff: &mut Backoff,
+    ) -> Result<(), String> {
+        let (p_prec, a_prec) =
+            BinanceClient::precisions(self.endpoints.rest.clone(), self.pair.clone()).await?;
+        status.report(
+            ConnectorState::PrecisionsFetched,
+            &format!("price={} amount={}", p_prec, a_prec),
         );
+        if let Some(r) = self.recorder.as_mut() {
+            r.set_precisions(p_prec, a_prec);
+        }
 
-        let pair = self.pair.clone();
-
-        tokio::spawn(async move {
-            let (p_prec, a_prec) = BinanceClient::precisions(pair).await;
-            info!("precisions price={} amount={}", p_prec, a_prec);
-            let (mut socket, _) = connect(Url::parse(&stream_url).unwrap()).expect("Can't connect");
-            info!("websocket connected");
-            loop {
-                let msg = socket.read_message().expect("Error reading message");
-                let parsed = json::parse(&msg.to_string()).unwrap();
-                let mut orders = Update::new(Exchange::Binance, p_prec, a_prec);
-                if parsed.has_key("asks") && parsed["asks"].is_array() {
-                    for i in 0..parsed["asks"].len() {
-                        if parsed["asks"][i].len() == 2 {
-                            orders.add_ask(
-                                &parsed["asks"][i][0].to_string(),
-                                &parsed["asks"][i][1].to_string(),
-                            );
-                        }
-                    }
+        let stream_url = format!(
+            "{}{}{}{}",
+            self.endpoints.websocket, "/ws/", self.pair, "@depth10@100ms"
+        );
+        let url = Url::parse(&stream_url).map_err(|e| e.to_string())?;
+        let (mut socket, _) = connect_async(url)
+            .await
+            .map_err(|e| format!("can't connect: {}", e))?;
+        status.report(ConnectorState::Subscribed, &stream_url);
+        backoff.reset();
+        let labels = [Exchange::Binance.to_string(), self.pair.clone()];
+        let messages = METRICS
+            .messages
+            .with_label_values(&[&labels[0], &labels[1]]);
+        let parse_errors = METRICS
+            .parse_errors
+            .with_label_values(&[&labels[0], &labels[1]]);
+        loop {
+            let msg = tokio::select! {
+                msg = socket.next() => msg
+                    .ok_or("connection closed".to_string())?
+                    .map_err(|e| format!("error reading message: {}", e))?,
+                _ = stop.stopped() => {
+                    let _ = socket.close(None).await;
+                    // leave no stale levels behind
+                    tx.send(Update::new(Exchange::Binance, p_prec, a_prec)).await;
+                    return Ok(());
                 }
-                if parsed.has_key("bids") && parsed["bids"].is_array() {
-                    for i in 0..parsed["bids"].len() {
-                        if parsed["bids"][i].len() == 2 {
-                            orders.add_bid(
-                                &parsed["bids"][i][0].to_string(),
-                                &parsed["bids"][i][1].to_string(),
-                            );
-                        }
+            };
+            let received = SystemTime::now();
+            // pings and pongs are answered by tungstenite, neither data nor errors
+            let Message::Text(frame) = msg else {
+                continue;
+            };
+            if let Some(r) = self.recorder.as_mut() {
+                r.record(&frame, received);
+            }
+            messages.inc();
+            match BinanceClient::parse(&frame, p_prec, a_prec) {
+                Some(mut orders) => {
+                    orders.stamp(received);
+                    // a full queue applies the overflow policy of the exchange
+                    if !tx.send(orders).await {
+                        return Ok(());
                     }
                 }
-                tx.send(orders).unwrap();
+                None => parse_errors.inc(),
             }
-        });
+        }
     }
 }
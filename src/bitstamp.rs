@@ -22,103 +22,423 @@
  */
 use crate::book::Exchange;
 use crate::book::Update;
-use crossbeam_channel::Sender;
-use log::info;
+use crate::endpoints::Endpoints;
+use crate::ingress::Ingress;
+use crate::metrics::METRICS;
+use crate::orderbook::ConnectorState;
+use crate::recorder::Recorder;
+use crate::status::{Backoff, StatusReporter, StopSignal};
+use futures::{SinkExt, StreamExt};
+use json::JsonValue;
 use std::cmp;
-use tungstenite::{connect, Message};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use url::Url;
 
+const HEARTBEAT: &str = r#"{"event":"bts:heartbeat"}"#;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// bitstamp answers every heartbeat, silence this long means a dead connection
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(20);
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+// a reconnect request this soon after the previous one waits a backoff
+const REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+// protocol messages of the websocket api besides the book data
+#[derive(Debug, PartialEq)]
+enum Control {
+    Subscribed,
+    Heartbeat,
+    // bitstamp is about to close the connection for maintenance
+    ReconnectRequested,
+    Error(String),
+}
+
+fn control(parsed: &JsonValue) -> Option<Control> {
+    match parsed["event"].as_str()? {
+        "bts:subscription_succeeded" => Some(Control::Subscribed),
+        "bts:heartbeat" => Some(Control::Heartbeat),
+        "bts:request_reconnect" => Some(Control::ReconnectRequested),
+        "bts:error" => Some(Control::Error(
+            parsed["data"]["message"]
+                .as_str()
+                .unwrap_or("unknown error")
+                .to_string(),
+        )),
+        _ => None,
+    }
+}
+
+// how a session ended without an error
+enum End {
+    // stopped or the book is gone, nothing left to feed
+    Done,
+    // bitstamp asked for a new connection, the session is still open
+    Reconnect(Box<Session>),
+}
+
 pub struct BitstampClient {
     pair: String,
+    endpoints: Endpoints,
+    recorder: Option<Recorder>,
 }
 
 impl BitstampClient {
     pub fn new(pair: String) -> BitstampClient {
         BitstampClient {
             pair: pair.to_string(),
+            endpoints: Endpoints::new(&Exchange::Bitstamp),
+            recorder: None,
         }
     }
 
-    pub async fn precisions(pair: String) -> (u64, u64) {
-        let body = reqwest::get("https://www.bitstamp.net/api/v2/trading-pairs-info")
+    pub fn set_endpoints(&mut self, endpoints: Endpoints) {
+        self.endpoints = endpoints;
+    }
+
+    pub fn record_to(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub async fn precisions(rest_url: String, pair: String) -> Result<(u64, u64), String> {
+        let body = reqwest::get(format!("{}{}", rest_url, "/api/v2/trading-pairs-info"))
             .await
-            .unwrap()
+            .map_err(|e| e.to_string())?
             .text()
             .await
-            .unwrap();
+            .map_err(|e| e.to_string())?;
 
-        let parsed = json::parse(body.as_str()).unwrap();
+        let parsed = json::parse(body.as_str()).map_err(|e| e.to_string())?;
 
         if parsed.is_array() {
             for i in 0..parsed.len() {
-                if parsed[i].has_key("url_symbol") && parsed[i]["url_symbol"].to_string() == pair {
-                    return (
-                        parsed[i]["base_decimals"].as_u64().unwrap(),
-                        parsed[i]["instant_order_counter_decimals"]
-                            .as_u64()
-                            .unwrap(),
+                if parsed[i].has_key("url_symbol") && parsed[i]["url_symbol"] == pair.as_str() {
+                    if let (Some(p_prec), Some(a_prec)) = (
+                        parsed[i]["base_decimals"].as_u64(),
+                        parsed[i]["instant_order_counter_decimals"].as_u64(),
+                    ) {
+                        return Ok((p_prec, a_prec));
+                    }
+                }
+            }
+        }
+
+        Err("cannot get precisions from bitstamp".to_string())
+    }
+
+    pub fn parse(frame: &str, p_prec: u64, a_prec: u64) -> Option<Update> {
+        BitstampClient::orders(&json::parse(frame).ok()?, p_prec, a_prec)
+    }
+
+    fn orders(parsed: &JsonValue, p_prec: u64, a_prec: u64) -> Option<Update> {
+        if !parsed.has_key("data")
+            || (!parsed["data"].has_key("asks") && !parsed["data"].has_key("bids"))
+        {
+            return None;
+        }
+        let mut orders = Update::new(Exchange::Bitstamp, p_prec, a_prec);
+        if let Some(us) = parsed["data"]["microtimestamp"]
+            .as_str()
+            .and_then(|us| us.parse().ok())
+        {
+            orders.set_event_time(UNIX_EPOCH + Duration::from_micros(us));
+        }
+        if parsed.has_key("data")
+            && parsed["data"].has_key("asks")
+            && parsed["data"]["asks"].is_array()
+        {
+            for i in 0..cmp::min(10, parsed["data"]["asks"].len()) {
+                if parsed["data"]["asks"][i].len() == 2 {
+                    orders.add_ask(
+                        &parsed["data"]["asks"][i][0].to_string(),
+                        &parsed["data"]["asks"][i][1].to_string(),
+                    );
+                }
+            }
+        }
+        if parsed.has_key("data")
+            && parsed["data"].has_key("bids")
+            && parsed["data"]["bids"].is_array()
+        {
+            for i in 0..cmp::min(10, parsed["data"]["bids"].len()) {
+                if parsed["data"]["bids"][i].len() == 2 {
+                    orders.add_bid(
+                        &parsed["data"]["bids"][i][0].to_string(),
+                        &parsed["data"]["bids"][i][1].to_string(),
                     );
                 }
             }
         }
+        Some(orders)
+    }
 
-        panic!("cannot get precisions from bitstamp");
+    pub fn do_main_loop(
+        self,
+        tx: Ingress,
+        status: StatusReporter,
+        stop: StopSignal,
+    ) -> JoinHandle<()> {
+        tokio::spawn(self.run(tx, status, stop))
+    }
+
+    async fn run(mut self, tx: Ingress, status: StatusReporter, stop: StopSignal) {
+        let mut backoff = Backoff::new();
+        // requests that follow each other closely wait a backoff of their own
+        let mut requests = Backoff::new();
+        let mut requested_at: Option<Instant> = None;
+        let mut next = None;
+        while !stop.is_stopped() {
+            let session = match next.take() {
+                Some(session) => session,
+                None => {
+                    status.report(ConnectorState::Connecting, &self.endpoints.websocket);
+                    match self.subscribe(&status, &stop).await {
+                        Ok(Some(session)) => session,
+                        Ok(None) => break,
+                        Err(e) => {
+                            status.report(ConnectorState::Failed, &e);
+                            retry(&status, &stop, &mut backoff).await;
+                            continue;
+                        }
+                    }
+                }
+            };
+            backoff.reset();
+            let mut old = match self.stream(session, &tx, &stop).await {
+                Ok(End::Done) => break,
+                Ok(End::Reconnect(old)) => old,
+                Err(e) => {
+                    status.report(ConnectorState::Failed, &e);
+                    retry(&status, &stop, &mut backoff).await;
+                    continue;
+                }
+            };
+            if requested_at.is_some_and(|at| at.elapsed() < REQUEST_WINDOW) {
+                let delay = requests.delay();
+                status.report(
+                    ConnectorState::Reconnecting,
+                    &format!("requested by bitstamp again, retry in {}s", delay.as_secs()),
+                );
+                old.close().await;
+                stop.sleep(delay).await;
+            } else {
+                requests.reset();
+                // the levels stay while the new session subscribes, the old
+                // one closes once it is there
+                status.report(ConnectorState::Reconnecting, "requested by bitstamp");
+                let opened = self.subscribe(&status, &stop).await;
+                if let Ok(None) = opened {
+                    old.clear(&tx).await;
+                }
+                old.close().await;
+                match opened {
+                    // none once stopped, which ends the loop
+                    Ok(session) => next = session,
+                    Err(e) => {
+                        status.report(ConnectorState::Failed, &e);
+                        retry(&status, &stop, &mut backoff).await;
+                    }
+                }
+            }
+            requested_at = Some(Instant::now());
+        }
+        status.report(ConnectorState::Disconnected, "stopped");
     }
 
-    pub fn do_main_loop(&self, tx: Sender<Update>) {
-        let stream_url = "wss://ws.bitstamp.net";
+    // a websocket subscribed to the book of the pair, None once stopped
+    async fn subscribe(
+        &mut self,
+        status: &StatusReporter,
+        stop: &StopSignal,
+    ) -> Result<Option<Session>, String> {
+        let (p_prec, a_prec) =
+            BitstampClient::precisions(self.endpoints.rest.clone(), self.pair.clone()).await?;
+        status.report(
+            ConnectorState::PrecisionsFetched,
+            &format!("price={} amount={}", p_prec, a_prec),
+        );
+        if let Some(r) = self.recorder.as_mut() {
+            r.set_precisions(p_prec, a_prec);
+        }
+
+        let url = Url::parse(&self.endpoints.websocket).map_err(|e| e.to_string())?;
+        let (mut socket, _) = connect_async(url)
+            .await
+            .map_err(|e| format!("can't connect: {}", e))?;
         let submessage = format!(
             "{}{}{}",
             r#"{"event":"bts:subscribe","data":{"channel":"order_book_"#, self.pair, "\"}}"
         );
+        socket
+            .send(Message::Text(submessage))
+            .await
+            .map_err(|e| format!("can't subscribe: {}", e))?;
+        // skip whatever comes before the answer to the subscription
+        let deadline = tokio::time::sleep(SUBSCRIBE_TIMEOUT);
+        tokio::pin!(deadline);
+        loop {
+            let msg = tokio::select! {
+                msg = socket.next() => msg
+                    .ok_or("connection closed".to_string())?
+                    .map_err(|e| format!("error reading message: {}", e))?,
+                _ = &mut deadline => return Err("no answer to the subscription".to_string()),
+                _ = stop.stopped() => {
+                    let _ = socket.close(None).await;
+                    return Ok(None);
+                }
+            };
+            let received = SystemTime::now();
+            let Message::Text(frame) = msg else {
+                continue;
+            };
+            if let Some(r) = self.recorder.as_mut() {
+                r.record(&frame, received);
+            }
+            match control(&json::parse(&frame).unwrap_or(json::Null)) {
+                Some(Control::Subscribed) => break,
+                Some(Control::Error(e)) => return Err(format!("subscription failed: {}", e)),
+                _ => {}
+            }
+        }
+        status.report(
+            ConnectorState::Subscribed,
+            &format!("order_book_{}", self.pair),
+        );
+        Ok(Some(Session {
+            socket,
+            p_prec,
+            a_prec,
+        }))
+    }
 
-        let pair = self.pair.clone();
-
-        tokio::spawn(async move {
-            let (p_prec, a_prec) = BitstampClient::precisions(pair.clone()).await;
-            info!("precisions price={} amount={}", p_prec, a_prec);
-            let (mut socket, _) = connect(Url::parse(&stream_url).unwrap()).expect("Can't connect");
-            info!("websocket connected");
-            socket
-                .write_message(Message::Text(submessage.into()))
-                .unwrap();
-            let msg = socket.read_message().expect("Error reading message");
-            let parsed = json::parse(&msg.to_string()).unwrap();
-            if parsed.has_key("event") && parsed["event"] == "bts:subscription_succeeded" {
-                info!("subscribed to {}", pair);
-                loop {
-                    let msg = socket.read_message().expect("Error reading message");
-                    let parsed = json::parse(&msg.to_string()).unwrap();
-                    let mut orders = Update::new(Exchange::Bitstamp, p_prec, a_prec);
-                    if parsed.has_key("data")
-                        && parsed["data"].has_key("asks")
-                        && parsed["data"]["asks"].is_array()
-                    {
-                        for i in 0..cmp::min(10, parsed["data"]["asks"].len()) {
-                            if parsed["data"]["asks"][i].len() == 2 {
-                                orders.add_ask(
-                                    &parsed["data"]["asks"][i][0].to_string(),
-                                    &parsed["data"]["asks"][i][1].to_string(),
-                                );
-                            }
-                        }
-                    }
-                    if parsed.has_key("data")
-                        && parsed["data"].has_key("bids")
-                        && parsed["data"]["bids"].is_array()
-                    {
-                        for i in 0..cmp::min(10, parsed["data"]["bids"].len()) {
-                            if parsed["data"]["bids"][i].len() == 2 {
-                                orders.add_bid(
-                                    &parsed["data"]["bids"][i][0].to_string(),
-                                    &parsed["data"]["bids"][i][1].to_string(),
-                                );
-                            }
-                        }
+    async fn stream(
+        &mut self,
+        mut session: Session,
+        tx: &Ingress,
+        stop: &StopSignal,
+    ) -> Result<End, String> {
+        let labels = [Exchange::Bitstamp.to_string(), self.pair.clone()];
+        let messages = METRICS
+            .messages
+            .with_label_values(&[&labels[0], &labels[1]]);
+        let parse_errors = METRICS
+            .parse_errors
+            .with_label_values(&[&labels[0], &labels[1]]);
+        let mut heartbeat =
+            tokio::time::interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+        let mut last_frame = Instant::now();
+        loop {
+            let msg = tokio::select! {
+                msg = session.socket.next() => msg
+                    .ok_or("connection closed".to_string())?
+                    .map_err(|e| format!("error reading message: {}", e))?,
+                _ = tokio::time::sleep_until(last_frame + HEARTBEAT_TIMEOUT) => {
+                    return Err(format!("no frame for {}s", HEARTBEAT_TIMEOUT.as_secs()));
+                }
+                _ = heartbeat.tick() => {
+                    session
+                        .socket
+                        .send(Message::Text(HEARTBEAT.to_string()))
+                        .await
+                        .map_err(|e| format!("can't send heartbeat: {}", e))?;
+                    continue;
+                }
+                _ = stop.stopped() => {
+                    session.clear(tx).await;
+                    session.close().await;
+                    return Ok(End::Done);
+                }
+            };
+            let received = SystemTime::now();
+            last_frame = Instant::now();
+            // pings and pongs are answered by tungstenite, neither data nor errors
+            let Message::Text(frame) = msg else {
+                continue;
+            };
+            if let Some(r) = self.recorder.as_mut() {
+                r.record(&frame, received);
+            }
+            messages.inc();
+            let parsed = json::parse(&frame).unwrap_or(json::Null);
+            match control(&parsed) {
+                Some(Control::Subscribed) | Some(Control::Heartbeat) => continue,
+                Some(Control::ReconnectRequested) => return Ok(End::Reconnect(Box::new(session))),
+                Some(Control::Error(e)) => return Err(format!("bitstamp error: {}", e)),
+                None => {}
+            }
+            match BitstampClient::orders(&parsed, session.p_prec, session.a_prec) {
+                Some(mut orders) => {
+                    orders.stamp(received);
+                    // a full queue applies the overflow policy of the exchange
+                    if !tx.send(orders).await {
+                        return Ok(End::Done);
                     }
-                    tx.send(orders).unwrap();
                 }
+                None => parse_errors.inc(),
             }
-        });
+        }
+    }
+}
+
+// a websocket subscribed to the book of the pair
+struct Session {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    p_prec: u64,
+    a_prec: u64,
+}
+
+impl Session {
+    // leave no stale levels behind
+    async fn clear(&self, tx: &Ingress) {
+        tx.send(Update::new(Exchange::Bitstamp, self.p_prec, self.a_prec))
+            .await;
+    }
+
+    async fn close(&mut self) {
+        let _ = self.socket.close(None).await;
+    }
+}
+
+async fn retry(status: &StatusReporter, stop: &StopSignal, backoff: &mut Backoff) {
+    let delay = backoff.delay();
+    status.report(
+        ConnectorState::Reconnecting,
+        &format!("retry in {}s", delay.as_secs()),
+    );
+    stop.sleep(delay).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{bitstamp_depth, bitstamp_event};
+
+    fn control_of(frame: &str) -> Option<Control> {
+        control(&json::parse(frame).unwrap())
+    }
+
+    #[test]
+    fn test_control() {
+        let subscribed = bitstamp_event("bts:subscription_succeeded", json::object! {});
+        assert_eq!(control_of(&subscribed), Some(Control::Subscribed));
+        let heartbeat = bitstamp_event("bts:heartbeat", json::object! { status: "success" });
+        assert_eq!(control_of(&heartbeat), Some(Control::Heartbeat));
+        let reconnect = bitstamp_event("bts:request_reconnect", json::object! {});
+        assert_eq!(control_of(&reconnect), Some(Control::ReconnectRequested));
+        let error = bitstamp_event(
+            "bts:error",
+            json::object! { code: json::Null, message: "Bad subscription string." },
+        );
+        assert_eq!(
+            control_of(&error),
+            Some(Control::Error("Bad subscription string.".to_string()))
+        );
+        let depth = bitstamp_depth("ethbtc", &[("0.5", "3")], &[]);
+        assert_eq!(control_of(&depth), None);
+        assert!(BitstampClient::parse(&depth, 10, 10).is_some());
+        assert!(BitstampClient::parse(&heartbeat, 10, 10).is_none());
     }
 }
@@ -22,34 +22,71 @@
  */
 use crate::orderbook::{Level, Summary};
 use bigdecimal::{BigDecimal, ToPrimitive};
-use log::debug;
+use log::{debug, warn};
+use serde::Deserialize;
 use std::cmp::Ordering;
 use std::cmp::Reverse;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeMap, BinaryHeap};
 use std::ops::Sub;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 pub struct Book {
-    asks: BinaryHeap<Reverse<Entry>>,
-    bids: BinaryHeap<Entry>,
+    asks: BinaryHeap<Entry>,
+    bids: BinaryHeap<Reverse<Entry>>,
+    depth: usize,
+    // fraction of the price, by exchange
+    fees: BTreeMap<Exchange, BigDecimal>,
     summary: Summary,
 }
 
+impl Default for Book {
+    fn default() -> Book {
+        Book::with_depth(20)
+    }
+}
+
 impl Book {
     pub fn new() -> Book {
+        Book::default()
+    }
+
+    pub fn with_depth(depth: usize) -> Book {
         Book {
             asks: BinaryHeap::new(),
             bids: BinaryHeap::new(),
+            depth,
+            fees: BTreeMap::new(),
             summary: Summary::default(),
         }
     }
 
-    pub fn add_orders(&mut self, mut orders: Update) -> () {
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+    }
+
+    // levels of the exchange added from now on are net of the fee
+    pub fn set_fee(&mut self, exchange: Exchange, fee_bps: f64) {
+        match BigDecimal::from_str(&fee_bps.to_string()) {
+            Ok(bps) if fee_bps > 0.0 => {
+                self.fees.insert(exchange, bps / BigDecimal::from(10000));
+            }
+            _ => {
+                self.fees.remove(&exchange);
+            }
+        }
+    }
+
+    pub fn add_orders(&mut self, mut orders: Update) {
+        if let Some(fee) = self.fees.get(&orders.exchange) {
+            orders.apply_fee(fee);
+        }
+
         // remove existing orders orders.exchange
         debug!("remove {} orders", orders.exchange);
 
-        self.asks.retain(|Reverse(e)| e.exchange != orders.exchange);
-        self.bids.retain(|e| e.exchange != orders.exchange);
+        self.asks.retain(|e| e.exchange != orders.exchange);
+        self.bids.retain(|Reverse(e)| e.exchange != orders.exchange);
 
         // insert orders
         debug!(
@@ -58,7 +95,7 @@ impl Book {
             orders.exchange
         );
         for x in orders.asks.drain(..) {
-            self.asks.push(Reverse(x)); // asc
+            self.asks.push(x); // lowest first
         }
         debug!(
             "insert {} bids orders from {}",
@@ -66,42 +103,36 @@ impl Book {
             orders.exchange
         );
         for x in orders.bids.drain(..) {
-            self.bids.push(x); // desc
+            self.bids.push(Reverse(x)); // highest first
         }
 
         // create summary
         self.summary.bids.clear();
         self.summary.asks.clear();
 
-        // up top 20 asks
+        // up to depth asks
         let mut a = self.asks.clone();
-        for _ in 1..21 {
-            if let Some(Reverse(val)) = a.pop() {
-                self.summary.asks.insert(
-                    0,
-                    Level {
-                        exchange: val.exchange.to_string(),
-                        price: val.price.to_f64().unwrap(),
-                        amount: val.amount.to_f64().unwrap(),
-                    },
-                );
+        for _ in 0..self.depth {
+            if let Some(val) = a.pop() {
+                self.summary.asks.push(Level {
+                    exchange: val.exchange.to_string(),
+                    price: val.price.to_f64().unwrap(),
+                    amount: val.amount.to_f64().unwrap(),
+                });
             } else {
                 break;
             }
         }
 
-        // up to 20 bids
+        // up to depth bids
         let mut b = self.bids.clone();
-        for _ in 1..21 {
-            if let Some(val) = b.pop() {
-                self.summary.bids.insert(
-                    0,
-                    Level {
-                        exchange: val.exchange.to_string(),
-                        price: val.price.to_f64().unwrap(),
-                        amount: val.amount.to_f64().unwrap(),
-                    },
-                );
+        for _ in 0..self.depth {
+            if let Some(Reverse(val)) = b.pop() {
+                self.summary.bids.push(Level {
+                    exchange: val.exchange.to_string(),
+                    price: val.price.to_f64().unwrap(),
+                    amount: val.amount.to_f64().unwrap(),
+                });
             } else {
                 break;
             }
@@ -109,7 +140,7 @@ impl Book {
 
         // calculate spread
         match (self.asks.peek(), self.bids.peek()) {
-            (Some(Reverse(ask)), Some(bid)) => {
+            (Some(ask), Some(Reverse(bid))) => {
                 self.summary.spread = ask
                     .price
                     .clone()
@@ -118,8 +149,8 @@ impl Book {
                     .to_f64()
                     .unwrap();
             }
-            (None, Some(bid)) => self.summary.spread = bid.price.to_f64().unwrap() * (-1.0),
-            (Some(Reverse(ask)), None) => self.summary.spread = ask.price.to_f64().unwrap(),
+            (None, Some(Reverse(bid))) => self.summary.spread = -bid.price.to_f64().unwrap(),
+            (Some(ask), None) => self.summary.spread = ask.price.to_f64().unwrap(),
             (None, None) => self.summary.spread = 0.0,
         }
 
@@ -136,7 +167,8 @@ impl Book {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Eq)]
+#[derive(Debug, PartialEq, Clone, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Exchange {
     Binance,
     Bitstamp,
@@ -151,6 +183,18 @@ impl std::fmt::Display for Exchange {
     }
 }
 
+impl FromStr for Exchange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Exchange, String> {
+        match s {
+            "binance" => Ok(Exchange::Binance),
+            "bitstamp" => Ok(Exchange::Bitstamp),
+            _ => Err(format!("unknown exchange {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Eq)]
 pub struct Entry {
     price: BigDecimal,
@@ -158,6 +202,21 @@ pub struct Entry {
     exchange: Exchange,
 }
 
+// pipeline timestamps of the frame behind an update, when known
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timing {
+    // event time set by the exchange
+    pub event: Option<SystemTime>,
+    pub received: Option<SystemTime>,
+    pub parsed: Option<SystemTime>,
+}
+
+impl Timing {
+    pub fn span(from: Option<SystemTime>, to: Option<SystemTime>) -> Option<Duration> {
+        to?.duration_since(from?).ok()
+    }
+}
+
 #[derive(Debug)]
 pub struct Update {
     exchange: Exchange,
@@ -165,6 +224,7 @@ pub struct Update {
     asks: Vec<Entry>,
     price_prec: u64,
     amount_prec: u64,
+    timing: Timing,
 }
 
 impl Update {
@@ -173,45 +233,86 @@ impl Update {
             exchange: e,
             bids: Vec::new(),
             asks: Vec::new(),
-            price_prec: price_prec,
-            amount_prec: amount_prec,
+            price_prec,
+            amount_prec,
+            timing: Timing::default(),
         }
     }
 
+    pub fn exchange(&self) -> &Exchange {
+        &self.exchange
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    pub fn precisions(&self) -> (u64, u64) {
+        (self.price_prec, self.amount_prec)
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn set_event_time(&mut self, event: SystemTime) {
+        self.timing.event = Some(event);
+    }
+
+    // frame received at `received` and parsed just now
+    pub fn stamp(&mut self, received: SystemTime) {
+        self.timing.received = Some(received);
+        self.timing.parsed = Some(SystemTime::now());
+    }
+
     pub fn add_bid(&mut self, price: &str, amount: &str) {
-        self.bids.push(Entry {
-            price: BigDecimal::from_str(price)
-                .unwrap()
-                .with_prec(self.price_prec),
-            amount: BigDecimal::from_str(amount)
-                .unwrap()
-                .with_prec(self.amount_prec),
-            exchange: self.exchange.clone(),
-        });
+        if let Some(entry) = self.entry(price, amount) {
+            self.bids.push(entry);
+        }
     }
 
     pub fn add_ask(&mut self, price: &str, amount: &str) {
-        self.asks.push(Entry {
-            price: BigDecimal::from_str(price)
-                .unwrap()
-                .with_prec(self.price_prec),
-            amount: BigDecimal::from_str(amount)
-                .unwrap()
-                .with_prec(self.amount_prec),
-            exchange: self.exchange.clone(),
-        });
+        if let Some(entry) = self.entry(price, amount) {
+            self.asks.push(entry);
+        }
+    }
+
+    // what a taker pays for the asks and gets for the bids
+    fn apply_fee(&mut self, fee: &BigDecimal) {
+        let one = BigDecimal::from(1);
+        let (up, down) = (&one + fee, &one - fee);
+        for ask in self.asks.iter_mut() {
+            ask.price = (&ask.price * &up).with_prec(self.price_prec);
+        }
+        for bid in self.bids.iter_mut() {
+            bid.price = (&bid.price * &down).with_prec(self.price_prec);
+        }
+    }
+
+    fn entry(&self, price: &str, amount: &str) -> Option<Entry> {
+        match (BigDecimal::from_str(price), BigDecimal::from_str(amount)) {
+            (Ok(p), Ok(a)) => Some(Entry {
+                price: p.with_prec(self.price_prec),
+                amount: a.with_prec(self.amount_prec),
+                exchange: self.exchange.clone(),
+            }),
+            _ => {
+                warn!("skip invalid {} level {} {}", self.exchange, price, amount);
+                None
+            }
+        }
     }
 }
 
 impl PartialOrd for Entry {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.price.partial_cmp(&self.price)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Entry {
     fn cmp(&self, other: &Entry) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        other.price.cmp(&self.price)
     }
 }
 
@@ -385,6 +486,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_depth() {
+        let mut orders = Update::new(Exchange::Binance, 10, 10);
+        for price in ["1", "2", "3", "4"] {
+            orders.add_bid(price, "1");
+            orders.add_ask(&format!("1{}", price), "1");
+        }
+        let mut book = Book::with_depth(2);
+        book.add_orders(orders);
+
+        let prices = |levels: &Vec<Level>| levels.iter().map(|l| l.price).collect::<Vec<_>>();
+        assert_eq!(prices(&book.summary.bids), vec![4.0, 3.0]);
+        assert_eq!(prices(&book.summary.asks), vec![11.0, 12.0]);
+        assert_eq!(book.summary.spread, 7.0);
+    }
+
+    #[test]
+    fn test_invalid_level() {
+        let mut orders = Update::new(Exchange::Binance, 10, 10);
+        orders.add_bid("abc", "1");
+        orders.add_bid("2", "2");
+        let mut book = Book::new();
+        book.add_orders(orders);
+        assert_eq!(book.summary.bids.len(), 1);
+    }
+
+    #[test]
+    fn test_timing_span() {
+        let start = SystemTime::now();
+        let later = start + Duration::from_millis(3);
+        assert_eq!(
+            Timing::span(Some(start), Some(later)),
+            Some(Duration::from_millis(3))
+        );
+        assert_eq!(Timing::span(Some(later), Some(start)), None);
+        assert_eq!(Timing::span(None, Some(start)), None);
+
+        let mut orders = Update::new(Exchange::Bitstamp, 10, 10);
+        orders.set_event_time(start);
+        orders.stamp(later);
+        let timing = orders.timing();
+        assert_eq!(timing.event, Some(start));
+        assert_eq!(timing.received, Some(later));
+        assert!(timing.parsed.is_some());
+    }
+
     #[test]
     fn test_decimal() {
         let e = BigDecimal::from_str("0.00000030003");
@@ -475,4 +622,31 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_fees() {
+        let binance = |bid, ask| {
+            let mut orders = Update::new(Exchange::Binance, 10, 10);
+            orders.add_bid(bid, "1");
+            orders.add_ask(ask, "1");
+            orders
+        };
+        let mut book = Book::new();
+        book.set_fee(Exchange::Binance, 10.0);
+        book.add_orders(binance("100", "101"));
+        let mut orders = Update::new(Exchange::Bitstamp, 10, 10);
+        orders.add_bid("99.95", "1");
+        orders.add_ask("101.05", "1");
+        book.add_orders(orders);
+        // 0.1% takes binance behind bitstamp on both sides
+        assert_eq!(book.summary.bids[0].exchange, "bitstamp");
+        assert_eq!(book.summary.bids[1].price, 99.9);
+        assert_eq!(book.summary.asks[0].exchange, "bitstamp");
+        assert_eq!(book.summary.asks[1].price, 101.101);
+
+        book.set_fee(Exchange::Binance, 0.0);
+        book.add_orders(binance("100", "101"));
+        assert_eq!(book.summary.bids[0].price, 100.0);
+        assert_eq!(book.summary.asks[0].price, 101.0);
+    }
 }
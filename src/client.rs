@@ -20,26 +20,135 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
  * IN THE SOFTWARE.
  */
-use tonic::Request;
+use crate::export::{Exporter, Format};
+use clap::Parser;
+use infonode::sdk::{ClientConfig, Level, Missed, Subscription};
+use std::path::PathBuf;
+use std::time::Duration;
 
-pub mod orderbook {
-    tonic::include_proto!("orderbook");
-}
+mod export;
+mod ladder;
+mod load;
 
-use orderbook::{orderbook_aggregator_client::OrderbookAggregatorClient, Empty};
+#[derive(Parser, Debug)]
+#[command(
+    name = "infonode-client",
+    version,
+    about = "prints the book summaries streamed by infonode-server"
+)]
+struct Cli {
+    /// pair to stream, the first pair of the server when missing
+    pair: Option<String>,
+    /// server url, https when --ca is given
+    #[arg(long, default_value = "http://[::1]:1079")]
+    server: String,
+    /// pem ca that signed the server certificate, enables tls
+    #[arg(long, value_name = "FILE")]
+    ca: Option<PathBuf>,
+    /// pem client certificate for mutual tls
+    #[arg(long, value_name = "FILE", requires = "key")]
+    cert: Option<PathBuf>,
+    /// pem private key of the client certificate
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    key: Option<PathBuf>,
+    /// name in the server certificate, the server host by default
+    #[arg(long)]
+    domain: Option<String>,
+    /// api key sent as a bearer token
+    #[arg(long, env = "INFONODE_TOKEN")]
+    token: Option<String>,
+    /// full screen bid/ask ladder instead of one line per summary
+    #[arg(long)]
+    tui: bool,
+    /// write every summary to files in DIR
+    #[arg(long, value_name = "DIR")]
+    record_dir: Option<PathBuf>,
+    /// csv or parquet
+    #[arg(long, default_value = "csv", requires = "record_dir")]
+    record_format: Format,
+    /// levels of each side written per summary
+    #[arg(long, default_value_t = 10, requires = "record_dir")]
+    record_depth: usize,
+    /// start a new file past this size
+    #[arg(
+        long,
+        default_value_t = 256,
+        value_name = "MB",
+        requires = "record_dir"
+    )]
+    roll_mb: u64,
+    /// start a new file after this long
+    #[arg(
+        long,
+        default_value_t = 60,
+        value_name = "MINUTES",
+        requires = "record_dir"
+    )]
+    roll_minutes: u64,
+    /// open N concurrent streams and print rate, jitter and latency percentiles
+    #[arg(long, value_name = "N", conflicts_with_all = ["tui", "record_dir"])]
+    load: Option<usize>,
+    /// how long the load test runs
+    #[arg(long, default_value_t = 10, value_name = "SECS", requires = "load")]
+    duration: u64,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = OrderbookAggregatorClient::connect("http://[::1]:1079").await?;
-
-    let mut stream = client
-        .book_summary(Request::new(Empty {}))
-        .await?
-        .into_inner();
+    let cli = Cli::parse();
+    let config = ClientConfig {
+        server: cli.server,
+        ca: cli.ca,
+        cert: cli.cert,
+        key: cli.key,
+        domain: cli.domain,
+        token: cli.token,
+    };
 
-    //NOTE: client must always apply precisions!!!!
-    while let Some(s) = stream.message().await? {
-        println!("BookSummary = {:?}", s);
+    // no pair streams the first pair configured on the server,
+    // the sdk applies the exchange precisions to every level
+    let pair = cli.pair.unwrap_or_default();
+    if let Some(streams) = cli.load {
+        return load::run(config, pair, streams, Duration::from_secs(cli.duration)).await;
+    }
+    let mut subscription = Subscription::new(config, &pair);
+    let mut exporter = cli.record_dir.map(|dir| {
+        Exporter::new(&dir, &pair, cli.record_format, cli.record_depth).with_rotation(
+            cli.roll_mb * 1024 * 1024,
+            Duration::from_secs(cli.roll_minutes * 60),
+        )
+    });
+    // the ladder shows stale books itself, logs would garble it
+    if cli.tui {
+        return ladder::run(subscription, exporter).await;
+    }
+    // the sdk warns when the stream breaks and it reconnects
+    simple_logger::init_with_level(log::Level::Warn).unwrap();
+    loop {
+        // parquet files are only readable once closed
+        tokio::select! {
+            next = subscription.next() => next?,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        let book = subscription.book();
+        if let Some(exporter) = exporter.as_mut() {
+            exporter.record(book);
+        }
+        match subscription.missed() {
+            Some(Missed::Updates(n)) => println!("{} missed {} updates", book.pair, n),
+            Some(Missed::Unknown) => println!("{} updates may have been missed", book.pair),
+            None => {}
+        }
+        let level = |level: Option<&Level>| match level {
+            Some(l) => format!("{} x {} ({})", l.price, l.amount, l.exchange),
+            None => "-".to_string(),
+        };
+        println!(
+            "{} bid {} ask {} spread {}",
+            book.pair,
+            level(book.best_bid()),
+            level(book.best_ask()),
+            book.spread().map_or("-".to_string(), |s| s.to_string())
+        );
     }
-    Ok(())
 }
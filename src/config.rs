@@ -0,0 +1,705 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::book::Exchange;
+use crate::endpoints::Endpoints;
+use crate::ingress::Overflow;
+use crate::replay::ReplayMode;
+use crate::tls;
+use clap::Parser;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tonic::codegen::http::header::{HeaderName, HeaderValue};
+use tonic::codegen::http::Method;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+const MAX_DEPTH: usize = 1000;
+const INGRESS_QUEUE: usize = 256;
+
+#[derive(Parser, Debug, Default, Clone)]
+#[command(
+    name = "infonode-server",
+    version,
+    about = "order book aggregator grpc server"
+)]
+pub struct Cli {
+    /// pairs to aggregate, e.g. ethbtc (overrides the config file)
+    pub pairs: Vec<String>,
+    /// toml or yaml configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// grpc listen address
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// levels per side in every summary
+    #[arg(long)]
+    pub depth: Option<usize>,
+    /// error, warn, info, debug or trace
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// exchange to leave out, may be repeated
+    #[arg(long = "disable", value_name = "EXCHANGE")]
+    pub disabled: Vec<Exchange>,
+    #[arg(long, value_name = "URL")]
+    pub binance_rest: Option<String>,
+    #[arg(long, value_name = "URL")]
+    pub binance_ws: Option<String>,
+    #[arg(long, value_name = "URL")]
+    pub bitstamp_rest: Option<String>,
+    #[arg(long, value_name = "URL")]
+    pub bitstamp_ws: Option<String>,
+    /// block, conflate or drop updates that find their ingress queue full
+    #[arg(long, value_name = "POLICY")]
+    pub overflow: Option<Overflow>,
+    /// directory of the raw frame captures
+    #[arg(long, value_name = "DIR")]
+    pub record_dir: Option<PathBuf>,
+    /// record <exchange>:<pair>, may be repeated
+    #[arg(long, value_name = "EXCHANGE:PAIR")]
+    pub record: Vec<String>,
+    /// replay the captures in DIR instead of connecting to the exchanges
+    #[arg(long, value_name = "DIR")]
+    pub replay: Option<PathBuf>,
+    /// realtime, max or an acceleration like 10x
+    #[arg(long, value_name = "SPEED")]
+    pub replay_speed: Option<String>,
+    /// pem certificate chain served by the grpc server
+    #[arg(long, value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+    /// pem private key of the certificate
+    #[arg(long, value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+    /// pem ca that must sign the client certificates (mutual tls)
+    #[arg(long, value_name = "FILE")]
+    pub tls_client_ca: Option<PathBuf>,
+    /// send the pipeline latency of every summary
+    #[arg(long)]
+    pub summary_latency: bool,
+    /// seconds without a book before health reports NOT_SERVING
+    #[arg(long, value_name = "SECS")]
+    pub stale_after: Option<u64>,
+    /// serve prometheus metrics on http://ADDR/metrics
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_listen: Option<SocketAddr>,
+    /// stream summaries as json on ws://ADDR
+    #[arg(long, value_name = "ADDR")]
+    pub ws_listen: Option<SocketAddr>,
+    /// serve json snapshots on http://ADDR
+    #[arg(long, value_name = "ADDR")]
+    pub rest_listen: Option<SocketAddr>,
+    /// serve grpc-web to browsers from ORIGIN, * for any (repeatable)
+    #[arg(long, value_name = "ORIGIN")]
+    pub grpc_web_origin: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub pairs: Vec<String>,
+    pub depth: usize,
+    pub summary_latency: bool,
+    // seconds
+    pub stale_after: u64,
+    pub log: LogConfig,
+    pub exchanges: ExchangesConfig,
+    pub record: RecordConfig,
+    pub replay: Option<ReplayConfig>,
+    pub tls: Option<TlsConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub gateway: Option<GatewayConfig>,
+    pub rest: Option<RestConfig>,
+    pub grpc_web: Option<GrpcWebConfig>,
+    pub auth: AuthConfig,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangesConfig {
+    pub binance: ExchangeConfig,
+    pub bitstamp: ExchangeConfig,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangeConfig {
+    pub enabled: bool,
+    pub rest: Option<String>,
+    pub websocket: Option<String>,
+    // updates waiting between the connector and the book
+    pub queue: usize,
+    pub overflow: Overflow,
+    // taker fee in basis points, the book shows levels net of it
+    pub fee_bps: f64,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordConfig {
+    pub dir: PathBuf,
+    // <exchange>:<pair>
+    pub targets: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub dir: PathBuf,
+    #[serde(default = "realtime")]
+    pub speed: String,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // require client certificates signed by this ca
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: SocketAddr,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    pub listen: SocketAddr,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RestConfig {
+    pub listen: SocketAddr,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcWebConfig {
+    // origins allowed by cors, "*" for any
+    pub allow_origins: Vec<String>,
+}
+
+// no key: every client sees everything
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub keys: Vec<KeyConfig>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    // who the key was issued to, for the logs
+    pub name: String,
+    pub key: String,
+    // empty: every pair or exchange
+    #[serde(default)]
+    pub pairs: Vec<String>,
+    #[serde(default)]
+    pub exchanges: Vec<Exchange>,
+    pub max_depth: Option<usize>,
+    // summaries per second
+    pub max_rate: Option<f64>,
+}
+
+fn realtime() -> String {
+    "realtime".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "[::1]:1079".parse().unwrap(),
+            pairs: Vec::new(),
+            depth: 20,
+            summary_latency: false,
+            stale_after: 10,
+            log: LogConfig::default(),
+            exchanges: ExchangesConfig::default(),
+            record: RecordConfig::default(),
+            replay: None,
+            tls: None,
+            metrics: None,
+            gateway: None,
+            rest: None,
+            grpc_web: None,
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Default for ExchangeConfig {
+    fn default() -> ExchangeConfig {
+        ExchangeConfig {
+            enabled: true,
+            rest: None,
+            websocket: None,
+            queue: INGRESS_QUEUE,
+            overflow: Overflow::default(),
+            fee_bps: 0.0,
+        }
+    }
+}
+
+impl Default for RecordConfig {
+    fn default() -> RecordConfig {
+        RecordConfig {
+            dir: PathBuf::from("captures"),
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl ExchangesConfig {
+    pub fn get(&self, exchange: &Exchange) -> &ExchangeConfig {
+        match exchange {
+            Exchange::Binance => &self.binance,
+            Exchange::Bitstamp => &self.bitstamp,
+        }
+    }
+
+    pub fn get_mut(&mut self, exchange: &Exchange) -> &mut ExchangeConfig {
+        match exchange {
+            Exchange::Binance => &mut self.binance,
+            Exchange::Bitstamp => &mut self.bitstamp,
+        }
+    }
+}
+
+impl Config {
+    // config file first, then command line overrides, then validation
+    pub fn load(cli: Cli) -> Result<Config, String> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.merge(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read config {}: {}", path.display(), e))?;
+        let parsed = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+            _ => Err("expected a .toml, .yaml or .yml extension".to_string()),
+        };
+        parsed.map_err(|e| format!("invalid config {}: {}", path.display(), e))
+    }
+
+    fn merge(&mut self, cli: Cli) {
+        if !cli.pairs.is_empty() {
+            self.pairs = cli.pairs;
+        }
+        if let Some(listen) = cli.listen {
+            self.listen = listen;
+        }
+        if let Some(depth) = cli.depth {
+            self.depth = depth;
+        }
+        if cli.summary_latency {
+            self.summary_latency = true;
+        }
+        if let Some(stale_after) = cli.stale_after {
+            self.stale_after = stale_after;
+        }
+        if let Some(level) = cli.log_level {
+            self.log.level = level;
+        }
+        for exchange in cli.disabled {
+            self.exchanges.get_mut(&exchange).enabled = false;
+        }
+        let endpoints = [
+            (Exchange::Binance, cli.binance_rest, cli.binance_ws),
+            (Exchange::Bitstamp, cli.bitstamp_rest, cli.bitstamp_ws),
+        ];
+        for (exchange, rest, websocket) in endpoints {
+            let config = self.exchanges.get_mut(&exchange);
+            config.rest = rest.or(config.rest.take());
+            config.websocket = websocket.or(config.websocket.take());
+            if let Some(overflow) = cli.overflow {
+                config.overflow = overflow;
+            }
+        }
+        if let Some(dir) = cli.record_dir {
+            self.record.dir = dir;
+        }
+        self.record.targets.extend(cli.record);
+        if let Some(dir) = cli.replay {
+            self.replay = Some(ReplayConfig {
+                dir,
+                speed: realtime(),
+            });
+        }
+        if let (Some(replay), Some(speed)) = (self.replay.as_mut(), cli.replay_speed) {
+            replay.speed = speed;
+        }
+        if cli.tls_cert.is_some() || cli.tls_key.is_some() || cli.tls_client_ca.is_some() {
+            let tls = self.tls.get_or_insert_with(TlsConfig::default);
+            if let Some(cert) = cli.tls_cert {
+                tls.cert = cert;
+            }
+            if let Some(key) = cli.tls_key {
+                tls.key = key;
+            }
+            if let Some(ca) = cli.tls_client_ca {
+                tls.client_ca = Some(ca);
+            }
+        }
+        if let Some(listen) = cli.metrics_listen {
+            self.metrics = Some(MetricsConfig { listen });
+        }
+        if let Some(listen) = cli.ws_listen {
+            self.gateway = Some(GatewayConfig { listen });
+        }
+        if let Some(listen) = cli.rest_listen {
+            self.rest = Some(RestConfig { listen });
+        }
+        if !cli.grpc_web_origin.is_empty() {
+            self.grpc_web = Some(GrpcWebConfig {
+                allow_origins: cli.grpc_web_origin,
+            });
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pairs.is_empty() {
+            return Err("no pair configured, pass one like ethbtc or set pairs".to_string());
+        }
+        for (i, pair) in self.pairs.iter().enumerate() {
+            if pair.is_empty()
+                || !pair
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            {
+                return Err(format!(
+                    "invalid pair {:?}, expected lowercase like ethbtc",
+                    pair
+                ));
+            }
+            if self.pairs[..i].contains(pair) {
+                return Err(format!("pair {} configured twice", pair));
+            }
+        }
+        if self.depth == 0 || self.depth > MAX_DEPTH {
+            return Err(format!("depth must be between 1 and {}", MAX_DEPTH));
+        }
+        if self.stale_after == 0 {
+            return Err("stale_after must be at least 1 second".to_string());
+        }
+        self.log_level()?;
+        for exchange in [Exchange::Binance, Exchange::Bitstamp] {
+            self.endpoints(&exchange)?;
+            if self.exchanges.get(&exchange).queue == 0 {
+                return Err(format!("{} queue must hold at least 1 update", exchange));
+            }
+            let fee_bps = self.exchanges.get(&exchange).fee_bps;
+            if !(0.0..10000.0).contains(&fee_bps) {
+                return Err(format!("{} fee_bps must be between 0 and 10000", exchange));
+            }
+        }
+        if self.replay.is_none() && self.enabled().is_empty() {
+            return Err("every exchange is disabled".to_string());
+        }
+        for target in &self.record.targets {
+            self.record_target(target)?;
+        }
+        self.replay_mode()?;
+        for (i, key) in self.auth.keys.iter().enumerate() {
+            if key.name.is_empty() || key.key.is_empty() {
+                return Err("api keys need a name and a key".to_string());
+            }
+            if self.auth.keys[..i].iter().any(|k| k.key == key.key) {
+                return Err(format!("api key of {} configured twice", key.name));
+            }
+            if key.max_depth == Some(0) || key.max_rate.is_some_and(|rate| rate <= 0.0) {
+                return Err(format!(
+                    "max_depth and max_rate of {} must be positive",
+                    key.name
+                ));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            for (what, path) in [("certificate", &tls.cert), ("key", &tls.key)] {
+                if path.as_os_str().is_empty() {
+                    return Err(format!("tls {} missing", what));
+                }
+            }
+            tls::server_config(tls)?;
+        }
+        if let Some(grpc_web) = &self.grpc_web {
+            grpc_web.cors().map(drop)?;
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<log::Level, String> {
+        self.log
+            .level
+            .parse()
+            .map_err(|_| format!("invalid log level {}", self.log.level))
+    }
+
+    // the logger lets every record through and log::max_level does the
+    // filtering, so that a reload can raise the level as well as lower it
+    pub fn init_logger(&self) -> Result<(), String> {
+        let level = self.log_level()?;
+        simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Trace)
+            .init()
+            .map_err(|e| e.to_string())?;
+        log::set_max_level(level.to_level_filter());
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> Vec<Exchange> {
+        [Exchange::Binance, Exchange::Bitstamp]
+            .into_iter()
+            .filter(|e| self.exchanges.get(e).enabled)
+            .collect()
+    }
+
+    pub fn endpoints(&self, exchange: &Exchange) -> Result<Endpoints, String> {
+        let config = self.exchanges.get(exchange);
+        let mut endpoints = Endpoints::new(exchange);
+        if let Some(url) = &config.rest {
+            endpoints.set_rest(url)?;
+        }
+        if let Some(url) = &config.websocket {
+            endpoints.set_websocket(url)?;
+        }
+        Ok(endpoints)
+    }
+
+    pub fn replay_mode(&self) -> Result<Option<ReplayMode>, String> {
+        self.replay.as_ref().map(|r| r.speed.parse()).transpose()
+    }
+
+    pub fn records(&self, exchange: &Exchange, pair: &str) -> bool {
+        self.record
+            .targets
+            .iter()
+            .any(|t| self.record_target(t) == Ok((exchange.clone(), pair)))
+    }
+
+    fn record_target<'a>(&self, target: &'a str) -> Result<(Exchange, &'a str), String> {
+        let invalid = || {
+            format!(
+                "invalid record target {}, expected <exchange>:<pair>",
+                target
+            )
+        };
+        let (exchange, pair) = target.split_once(':').ok_or_else(invalid)?;
+        let exchange: Exchange = exchange.parse()?;
+        if !self.pairs.iter().any(|p| p == pair) {
+            return Err(format!("record target {} is not a configured pair", target));
+        }
+        Ok((exchange, pair))
+    }
+}
+
+impl GrpcWebConfig {
+    // browsers send the grpc-web headers and need the grpc status back
+    pub fn cors(&self) -> Result<CorsLayer, String> {
+        if self.allow_origins.is_empty() {
+            return Err("grpc_web needs at least one origin".to_string());
+        }
+        let origins = if self.allow_origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .allow_origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin)
+                        .map_err(|_| format!("invalid grpc_web origin {}", origin))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            AllowOrigin::list(origins)
+        };
+        let headers = [
+            "authorization",
+            "content-type",
+            "grpc-timeout",
+            "x-api-key",
+            "x-grpc-web",
+            "x-user-agent",
+        ];
+        let exposed = ["grpc-status", "grpc-message", "grpc-status-details-bin"];
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::POST])
+            .allow_headers(headers.map(HeaderName::from_static))
+            .expose_headers(exposed.map(HeaderName::from_static))
+            .max_age(Duration::from_secs(24 * 60 * 60)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from([&["infonode-server"], args].concat())
+    }
+
+    #[test]
+    fn test_cli_pair() {
+        let config = Config::load(cli(&["ethbtc"])).unwrap();
+        assert_eq!(config.pairs, vec!["ethbtc"]);
+        assert_eq!(config.listen, "[::1]:1079".parse().unwrap());
+        assert_eq!(config.depth, 20);
+        assert_eq!(
+            config.enabled(),
+            vec![Exchange::Binance, Exchange::Bitstamp]
+        );
+        assert_eq!(config.exchanges.binance.overflow, Overflow::Conflate);
+        let config = Config::load(cli(&["ethbtc", "--overflow", "block"])).unwrap();
+        assert_eq!(config.exchanges.bitstamp.overflow, Overflow::Block);
+    }
+
+    #[test]
+    fn test_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = "127.0.0.1:5000"
+            pairs = ["ethbtc", "btcusdt"]
+            depth = 5
+
+            [log]
+            level = "debug"
+
+            [exchanges.bitstamp]
+            enabled = false
+
+            [exchanges.binance]
+            websocket = "ws://127.0.0.1:9443"
+            queue = 16
+            overflow = "drop"
+            fee_bps = 7.5
+
+            [record]
+            targets = ["binance:btcusdt"]
+
+            [metrics]
+            listen = "127.0.0.1:9090"
+
+            [[auth.keys]]
+            name = "desk"
+            key = "secret"
+            pairs = ["ethbtc"]
+            exchanges = ["bitstamp"]
+            max_rate = 2.5
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.log_level(), Ok(log::Level::Debug));
+        assert_eq!(config.enabled(), vec![Exchange::Binance]);
+        assert_eq!(
+            config.endpoints(&Exchange::Binance).unwrap().websocket,
+            "ws://127.0.0.1:9443"
+        );
+        assert_eq!(config.exchanges.binance.queue, 16);
+        assert_eq!(config.exchanges.binance.overflow, Overflow::Drop);
+        assert_eq!(config.exchanges.bitstamp.overflow, Overflow::Conflate);
+        assert_eq!(config.exchanges.binance.fee_bps, 7.5);
+        assert_eq!(config.exchanges.bitstamp.fee_bps, 0.0);
+        assert!(config.records(&Exchange::Binance, "btcusdt"));
+        assert!(!config.records(&Exchange::Binance, "ethbtc"));
+        assert_eq!(
+            config.metrics.unwrap().listen,
+            "127.0.0.1:9090".parse().unwrap()
+        );
+        assert_eq!(config.auth.keys[0].exchanges, vec![Exchange::Bitstamp]);
+        assert_eq!(config.auth.keys[0].max_rate, Some(2.5));
+        assert_eq!(config.auth.keys[0].max_depth, None);
+    }
+
+    #[test]
+    fn test_yaml() {
+        let config: Config =
+            serde_yaml::from_str("pairs: [ethbtc]\nreplay:\n  dir: captures\n  speed: 10x\n")
+                .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.replay_mode(),
+            Ok(Some(ReplayMode::Accelerated(10.0)))
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Config::load(cli(&[])).is_err());
+        assert!(Config::load(cli(&["ETH-BTC"])).is_err());
+        assert!(Config::load(cli(&["ethbtc", "--depth", "0"])).is_err());
+        assert!(Config::load(cli(&["ethbtc", "--log-level", "loud"])).is_err());
+        assert!(Config::load(cli(&["ethbtc", "--record", "binance:btcusdt"])).is_err());
+        assert!(Config::load(cli(&["ethbtc", "--binance-ws", "http://x"])).is_err());
+        assert!(Config::load(cli(&[
+            "ethbtc",
+            "--disable",
+            "binance",
+            "--disable",
+            "bitstamp"
+        ]))
+        .is_err());
+        assert!(Config::load(cli(&["ethbtc", "--tls-cert", "missing.pem"])).is_err());
+        assert!(Config::load(cli(&["ethbtc", "--grpc-web-origin", "bad\norigin"])).is_err());
+        assert!(Config::load(cli(&["ethbtc", "--grpc-web-origin", "*"])).is_ok());
+        assert!(toml::from_str::<Config>(
+            "pairs = [\"ethbtc\"]\n[exchanges.binance]\noverflow = \"spill\""
+        )
+        .is_err());
+        let config: Config =
+            toml::from_str("pairs = [\"ethbtc\"]\n[exchanges.binance]\nqueue = 0").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config =
+            toml::from_str("pairs = [\"ethbtc\"]\n[exchanges.bitstamp]\nfee_bps = -1").unwrap();
+        assert!(config.validate().is_err());
+        assert!(toml::from_str::<Config>("pairs = [\"ethbtc\"]\nport = 1").is_err());
+    }
+}
@@ -0,0 +1,80 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::book::Exchange;
+use url::Url;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Endpoints {
+    pub rest: String,
+    pub websocket: String,
+}
+
+impl Endpoints {
+    pub fn new(exchange: &Exchange) -> Endpoints {
+        match exchange {
+            Exchange::Binance => Endpoints {
+                rest: "https://api.binance.com".to_string(),
+                websocket: "wss://stream.binance.com:9443".to_string(),
+            },
+            Exchange::Bitstamp => Endpoints {
+                rest: "https://www.bitstamp.net".to_string(),
+                websocket: "wss://ws.bitstamp.net".to_string(),
+            },
+        }
+    }
+
+    pub fn set_rest(&mut self, url: &str) -> Result<(), String> {
+        self.rest = base_url(url, &["http", "https"])?;
+        Ok(())
+    }
+
+    pub fn set_websocket(&mut self, url: &str) -> Result<(), String> {
+        self.websocket = base_url(url, &["ws", "wss"])?;
+        Ok(())
+    }
+}
+
+// exchange paths are appended to the base, so the trailing slash is dropped
+fn base_url(url: &str, schemes: &[&str]) -> Result<String, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+    if !schemes.contains(&parsed.scheme()) {
+        return Err(format!("url {} must use one of {:?}", url, schemes));
+    }
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoints() {
+        let mut endpoints = Endpoints::new(&Exchange::Binance);
+        assert!(endpoints.set_rest("http://127.0.0.1:8080/").is_ok());
+        assert!(endpoints.set_websocket("ws://127.0.0.1:8081").is_ok());
+        assert_eq!(endpoints.rest, "http://127.0.0.1:8080");
+        assert_eq!(endpoints.websocket, "ws://127.0.0.1:8081");
+        assert!(endpoints.set_rest("ws://127.0.0.1:8080").is_err());
+        assert!(endpoints.set_websocket("not a url").is_err());
+    }
+}
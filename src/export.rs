@@ -0,0 +1,423 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use bigdecimal::{BigDecimal, ToPrimitive};
+use infonode::sdk::{Level, LocalBook};
+use log::{info, warn};
+use parquet::basic::Compression;
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// one row per summary: the receive time, the sequence, the spread and the
+// exchange, price and amount of every level up to the depth, best first:
+//
+//   timestamp_ns,sequence,spread,bid_0_exchange,bid_0_price,bid_0_amount,...,ask_0_exchange,...
+//
+// levels the book doesn't have are left empty (null in parquet).
+const MAX_FILE_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_FILE_AGE: Duration = Duration::from_secs(3600);
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// parquet rows are only readable once their row group is written
+const ROW_GROUP_ROWS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(format!("unknown format {}, expected csv or parquet", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Format::Csv => write!(f, "csv"),
+            Format::Parquet => write!(f, "parquet"),
+        }
+    }
+}
+
+enum Value {
+    Int(i64),
+    Text(Option<String>),
+    Decimal(Option<BigDecimal>),
+}
+
+impl Value {
+    fn to_csv(&self) -> String {
+        match self {
+            Value::Int(i) => i.to_string(),
+            Value::Text(text) => text.clone().unwrap_or_default(),
+            Value::Decimal(decimal) => decimal.as_ref().map(|d| d.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+enum Output {
+    Csv(csv::Writer<File>),
+    Parquet(SerializedFileWriter<File>, Vec<Vec<Value>>),
+}
+
+pub struct Exporter {
+    dir: PathBuf,
+    pair: String,
+    format: Format,
+    depth: usize,
+    max_bytes: u64,
+    max_age: Duration,
+    output: Option<Output>,
+    opened_at: Instant,
+    flushed_at: Instant,
+    written: u64,
+}
+
+impl Exporter {
+    pub fn new(dir: &Path, pair: &str, format: Format, depth: usize) -> Exporter {
+        Exporter {
+            dir: dir.to_path_buf(),
+            pair: pair.to_string(),
+            format,
+            depth,
+            max_bytes: MAX_FILE_BYTES,
+            max_age: MAX_FILE_AGE,
+            output: None,
+            opened_at: Instant::now(),
+            flushed_at: Instant::now(),
+            written: 0,
+        }
+    }
+
+    pub fn with_rotation(mut self, max_bytes: u64, max_age: Duration) -> Exporter {
+        self.max_bytes = max_bytes;
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn record(&mut self, book: &LocalBook) {
+        if let Err(e) = self.write(book) {
+            warn!("cannot record {} summary: {}", self.pair, e);
+        }
+    }
+
+    fn write(&mut self, book: &LocalBook) -> io::Result<()> {
+        if self.output.is_none()
+            || self.written >= self.max_bytes
+            || self.opened_at.elapsed() >= self.max_age
+        {
+            self.rotate(book)?;
+        }
+        let row = row(book, self.depth);
+        match self.output.as_mut().unwrap() {
+            Output::Csv(writer) => {
+                let fields: Vec<String> = row.iter().map(Value::to_csv).collect();
+                self.written += fields.iter().map(|f| f.len() as u64 + 1).sum::<u64>();
+                writer.write_record(&fields)?;
+                if self.flushed_at.elapsed() >= FLUSH_INTERVAL {
+                    writer.flush()?;
+                    self.flushed_at = Instant::now();
+                }
+            }
+            Output::Parquet(writer, rows) => {
+                rows.push(row);
+                if rows.len() >= ROW_GROUP_ROWS {
+                    write_row_group(writer, rows).map_err(io::Error::other)?;
+                    self.written = writer.bytes_written() as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self, book: &LocalBook) -> io::Result<()> {
+        self.close();
+        self.written = 0;
+        // the server names the pair when the client asked for its default
+        if self.pair.is_empty() {
+            self.pair = book.pair.clone();
+        }
+        fs::create_dir_all(&self.dir)?;
+        let path = self
+            .dir
+            .join(format!("{}-{}.{}", self.pair, now_ns(), self.format));
+        let file = File::create(&path)?;
+        info!("recording {} summaries to {}", self.pair, path.display());
+        let columns = columns(self.depth);
+        self.output = Some(match self.format {
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                writer.write_record(&columns)?;
+                self.written = columns.iter().map(|c| c.len() as u64 + 1).sum();
+                Output::Csv(writer)
+            }
+            Format::Parquet => {
+                let schema = parse_message_type(&schema(&columns)).map_err(io::Error::other)?;
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let writer =
+                    SerializedFileWriter::new(file, Arc::new(schema), Arc::new(properties))
+                        .map_err(io::Error::other)?;
+                Output::Parquet(writer, Vec::new())
+            }
+        });
+        self.opened_at = Instant::now();
+        self.flushed_at = Instant::now();
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        let closed = match self.output.take() {
+            Some(Output::Csv(mut writer)) => writer.flush().map_err(|e| e.to_string()),
+            Some(Output::Parquet(mut writer, mut rows)) => write_row_group(&mut writer, &mut rows)
+                .and_then(|_| writer.close().map(drop))
+                .map_err(|e| e.to_string()),
+            None => Ok(()),
+        };
+        if let Err(e) = closed {
+            warn!("cannot close {} summaries: {}", self.pair, e);
+        }
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+pub fn columns(depth: usize) -> Vec<String> {
+    let mut columns = vec![
+        "timestamp_ns".to_string(),
+        "sequence".into(),
+        "spread".into(),
+    ];
+    for side in ["bid", "ask"] {
+        for i in 0..depth {
+            for field in ["exchange", "price", "amount"] {
+                columns.push(format!("{}_{}_{}", side, i, field));
+            }
+        }
+    }
+    columns
+}
+
+fn schema(columns: &[String]) -> String {
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|column| match column.as_str() {
+            "timestamp_ns" | "sequence" => format!("required int64 {};", column),
+            c if c.ends_with("_exchange") => format!("optional binary {} (UTF8);", column),
+            _ => format!("optional double {};", column),
+        })
+        .collect();
+    format!("message summary {{ {} }}", fields.join(" "))
+}
+
+fn row(book: &LocalBook, depth: usize) -> Vec<Value> {
+    let mut row = vec![
+        Value::Int(now_ns() as i64),
+        Value::Int(book.sequence as i64),
+        Value::Decimal(book.spread()),
+    ];
+    for levels in [&book.bids, &book.asks] {
+        for i in 0..depth {
+            let level: Option<&Level> = levels.get(i);
+            row.push(Value::Text(level.map(|l| l.exchange.clone())));
+            row.push(Value::Decimal(level.map(|l| l.price.clone())));
+            row.push(Value::Decimal(level.map(|l| l.amount.clone())));
+        }
+    }
+    row
+}
+
+fn write_row_group(
+    writer: &mut SerializedFileWriter<File>,
+    rows: &mut Vec<Vec<Value>>,
+) -> parquet::errors::Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        let values = rows.iter().map(|row| &row[index]);
+        // optional columns only get their present values, nulls are a 0 level
+        let levels: Vec<i16> = values
+            .clone()
+            .map(|value| match value {
+                Value::Text(None) | Value::Decimal(None) => 0,
+                _ => 1,
+            })
+            .collect();
+        match column.untyped() {
+            ColumnWriter::Int64ColumnWriter(writer) => {
+                let ints: Vec<i64> = values
+                    .filter_map(|value| match value {
+                        Value::Int(i) => Some(*i),
+                        _ => None,
+                    })
+                    .collect();
+                writer.write_batch(&ints, None, None)?;
+            }
+            ColumnWriter::DoubleColumnWriter(writer) => {
+                let doubles: Vec<f64> = values
+                    .filter_map(|value| match value {
+                        Value::Decimal(Some(d)) => d.to_f64(),
+                        _ => None,
+                    })
+                    .collect();
+                writer.write_batch(&doubles, Some(&levels), None)?;
+            }
+            ColumnWriter::ByteArrayColumnWriter(writer) => {
+                let texts: Vec<ByteArray> = values
+                    .filter_map(|value| match value {
+                        Value::Text(Some(text)) => Some(ByteArray::from(text.as_str())),
+                        _ => None,
+                    })
+                    .collect();
+                writer.write_batch(&texts, Some(&levels), None)?;
+            }
+            _ => unreachable!("no other column types in the schema"),
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    rows.clear();
+    Ok(())
+}
+
+fn now_ns() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn book(sequence: u64) -> LocalBook {
+        let level = |exchange: &str, price: &str, amount: &str| Level {
+            exchange: exchange.to_string(),
+            price: BigDecimal::from_str(price).unwrap(),
+            amount: BigDecimal::from_str(amount).unwrap(),
+        };
+        LocalBook {
+            pair: "ethbtc".to_string(),
+            bids: vec![level("binance", "0.0650", "1.5")],
+            asks: vec![
+                level("bitstamp", "0.0652", "2"),
+                level("binance", "0.0653", "0.25"),
+            ],
+            sequence,
+            ..LocalBook::default()
+        }
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_csv() {
+        let dir = std::env::temp_dir().join(format!("infonode-export-csv-{}", now_ns()));
+        let mut exporter = Exporter::new(&dir, "", Format::Csv, 2).with_rotation(300, MAX_FILE_AGE);
+        for sequence in 1..=3 {
+            exporter.record(&book(sequence));
+        }
+        drop(exporter);
+
+        // the header and two rows fill the first file past 300 bytes
+        let files = files(&dir);
+        assert_eq!(files.len(), 2);
+        assert!(files[0]
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("ethbtc-"));
+        let mut reader = csv::Reader::from_path(&files[0]).unwrap();
+        assert_eq!(reader.headers().unwrap().len(), 3 + 2 * 2 * 3);
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        let fields: Vec<&str> = rows[0].iter().collect();
+        assert_eq!(fields[1..3], ["1", "0.0002"]);
+        assert_eq!(fields[3..9], ["binance", "0.0650", "1.5", "", "", ""]);
+        assert_eq!(fields[9..12], ["bitstamp", "0.0652", "2"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parquet() {
+        let dir = std::env::temp_dir().join(format!("infonode-export-parquet-{}", now_ns()));
+        let mut exporter = Exporter::new(&dir, "ethbtc", Format::Parquet, 2);
+        for sequence in 1..=3 {
+            exporter.record(&book(sequence));
+        }
+        drop(exporter);
+
+        let files = files(&dir);
+        assert_eq!(files.len(), 1);
+        let reader = SerializedFileReader::new(File::open(&files[0]).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+        let rows: Vec<String> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect();
+        assert!(rows[2].contains("sequence: 3"), "{}", rows[2]);
+        assert!(
+            rows[0].contains("bid_0_exchange: \"binance\""),
+            "{}",
+            rows[0]
+        );
+        assert!(rows[0].contains("bid_1_price: null"), "{}", rows[0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
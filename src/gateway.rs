@@ -0,0 +1,233 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::auth::{bearer, Authenticator, Entitlements};
+use crate::orderbook::{ExchangeStatus, Level, Summary};
+use crate::pipeline::Registry;
+use futures::{SinkExt, StreamExt};
+use json::JsonValue;
+use log::{debug, info, warn};
+use std::sync::{Arc, RwLock};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamMap;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use url::Url;
+
+// websocket clients subscribe to pairs and get the summaries as json:
+//   -> {"op":"subscribe","pair":"ethbtc","depth":10}
+//   -> {"op":"unsubscribe","pair":"ethbtc"}
+//   <- {"type":"subscribed"|"unsubscribed"|"summary"|"error", ...}
+// the gateway stops accepting and closes its sessions on shutdown
+pub async fn serve(
+    listener: TcpListener,
+    registry: Arc<RwLock<Registry>>,
+    authenticator: Authenticator,
+    mut shutdown: watch::Receiver<()>,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("websocket gateway listening on ws://{}", addr);
+    }
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    debug!("websocket client {}", addr);
+                    tokio::spawn(session(
+                        stream,
+                        registry.clone(),
+                        authenticator.clone(),
+                        shutdown.clone(),
+                    ));
+                }
+                Err(e) => warn!("websocket accept failed: {}", e),
+            },
+            _ = shutdown.changed() => break,
+        }
+    }
+    info!("websocket gateway stopped");
+}
+
+#[allow(clippy::result_large_err)]
+async fn session(
+    stream: TcpStream,
+    registry: Arc<RwLock<Registry>>,
+    authenticator: Authenticator,
+    mut shutdown: watch::Receiver<()>,
+) {
+    // the api key comes as a header or, for browsers, as ?token=
+    let mut token = None;
+    let callback = |request: &Request, response: Response| {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let query = Url::parse(&format!("ws://localhost{}", request.uri()))
+            .ok()
+            .and_then(|url| {
+                url.query_pairs()
+                    .find(|(k, _)| k == "token")
+                    .map(|(_, v)| v.to_string())
+            });
+        let authorization = header("authorization");
+        let api_key = header("x-api-key").or(query);
+        token = bearer(authorization.as_deref(), api_key.as_deref()).map(str::to_string);
+        Ok(response)
+    };
+    let Ok(mut socket) = tokio_tungstenite::accept_hdr_async(stream, callback).await else {
+        return;
+    };
+    let entitlements = match authenticator.entitlements(token.as_deref()) {
+        Ok(entitlements) => entitlements,
+        Err(status) => {
+            let _ = send(&mut socket, error(status.message())).await;
+            let _ = socket.close(None).await;
+            return;
+        }
+    };
+
+    let mut subscriptions = StreamMap::new();
+    loop {
+        tokio::select! {
+            msg = socket.next() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                };
+                let reply = command(&text, &registry, &entitlements, &mut subscriptions);
+                if send(&mut socket, reply).await.is_err() {
+                    return;
+                }
+            }
+            Some((pair, msg)) = subscriptions.next() => {
+                let reply = match msg {
+                    Ok(summary) => {
+                        let mut msg = summary_json(&summary);
+                        msg["type"] = "summary".into();
+                        msg
+                    }
+                    // the pair went away, so does the subscription
+                    Err(status) => {
+                        subscriptions.remove(&pair);
+                        error(status.message())
+                    }
+                };
+                if send(&mut socket, reply).await.is_err() {
+                    return;
+                }
+            }
+            _ = shutdown.changed() => {
+                let _ = socket.close(None).await;
+                return;
+            }
+        }
+    }
+}
+
+type Subscriptions = StreamMap<String, ReceiverStream<Result<Summary, tonic::Status>>>;
+
+fn command(
+    text: &str,
+    registry: &Arc<RwLock<Registry>>,
+    entitlements: &Entitlements,
+    subscriptions: &mut Subscriptions,
+) -> JsonValue {
+    let Ok(parsed) = json::parse(text) else {
+        return error("invalid json");
+    };
+    let pair = parsed["pair"].as_str().unwrap_or_default();
+    match parsed["op"].as_str() {
+        Some("subscribe") => {
+            let mut entitlements = entitlements.clone();
+            if let Some(depth) = parsed["depth"].as_usize() {
+                if depth == 0 {
+                    return error("depth must be positive");
+                }
+                entitlements.limit_depth(depth);
+            }
+            match registry.read().unwrap().summaries(pair, &entitlements) {
+                Ok((pair, rx)) => {
+                    subscriptions.insert(pair.clone(), ReceiverStream::new(rx));
+                    json::object! { type: "subscribed", pair: pair }
+                }
+                Err(status) => error(status.message()),
+            }
+        }
+        Some("unsubscribe") => {
+            let pair = match pair {
+                "" => registry.read().unwrap().default_pair.clone(),
+                pair => pair.to_string(),
+            };
+            match subscriptions.remove(&pair) {
+                Some(_) => json::object! { type: "unsubscribed", pair: pair },
+                None => error(&format!("not subscribed to {}", pair)),
+            }
+        }
+        _ => error("expected op subscribe or unsubscribe"),
+    }
+}
+
+async fn send(socket: &mut WebSocketStream<TcpStream>, msg: JsonValue) -> Result<(), String> {
+    socket
+        .send(Message::Text(msg.dump()))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn error(message: &str) -> JsonValue {
+    json::object! { type: "error", message: message }
+}
+
+fn levels(levels: &[Level]) -> JsonValue {
+    levels
+        .iter()
+        .map(|l| json::object! { exchange: l.exchange.clone(), price: l.price, amount: l.amount })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+pub fn status_json(status: &ExchangeStatus) -> JsonValue {
+    json::object! {
+        exchange: status.exchange.clone(),
+        state: status.state().as_str_name(),
+        detail: status.detail.clone(),
+        timestamp: status.timestamp,
+    }
+}
+
+pub fn summary_json(summary: &Summary) -> JsonValue {
+    json::object! {
+        pair: summary.pair.clone(),
+        spread: summary.spread,
+        bids: levels(&summary.bids),
+        asks: levels(&summary.asks),
+        exchanges: summary.exchanges.iter().map(status_json).collect::<Vec<_>>(),
+        sequence: summary.sequence,
+    }
+}
@@ -0,0 +1,286 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::book::{Exchange, Update};
+use crate::metrics::METRICS;
+use log::{info, warn};
+use prometheus::{IntCounter, IntGauge};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+// what a connector does with an update when its queue to the book is full
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    // wait for room, holding the websocket
+    Block,
+    // replace the queued updates with the new one, safe since both exchanges
+    // send full snapshots of their top levels
+    #[default]
+    Conflate,
+    // lose the new update
+    Drop,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "block" => Ok(Overflow::Block),
+            "conflate" => Ok(Overflow::Conflate),
+            "drop" => Ok(Overflow::Drop),
+            _ => Err(format!(
+                "unknown overflow {}, expected block, conflate or drop",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Overflow::Block => write!(f, "block"),
+            Overflow::Conflate => write!(f, "conflate"),
+            Overflow::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+struct Queue {
+    updates: Mutex<VecDeque<Update>>,
+    capacity: usize,
+    // wakes the forwarder
+    pushed: Notify,
+    // wakes a blocked connector
+    popped: Notify,
+    // the connector is done or the book is gone
+    closed: AtomicBool,
+}
+
+// the connector side of a bounded queue to the book
+pub struct Ingress {
+    queue: Arc<Queue>,
+    exchange: Exchange,
+    pair: String,
+    overflow: Overflow,
+    overflows: IntCounter,
+    overloaded: IntGauge,
+}
+
+// starts the task that moves the updates of one connector to the book
+pub fn ingress(
+    exchange: Exchange,
+    pair: &str,
+    capacity: usize,
+    overflow: Overflow,
+    orders_tx: Sender<Update>,
+) -> (Ingress, JoinHandle<()>) {
+    let queue = Arc::new(Queue {
+        updates: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        pushed: Notify::new(),
+        popped: Notify::new(),
+        closed: AtomicBool::new(false),
+    });
+    let labels = [exchange.to_string(), pair.to_string()];
+    let depth = METRICS.queue_depth.with_label_values(&[pair, &labels[0]]);
+    let overloaded = METRICS
+        .ingress_overloaded
+        .with_label_values(&[&labels[0], &labels[1]]);
+    overloaded.set(0);
+    let forwarder = queue.clone();
+    let handle = tokio::spawn(async move {
+        loop {
+            let next = forwarder.updates.lock().unwrap().pop_front();
+            let Some(update) = next else {
+                if forwarder.closed.load(Ordering::SeqCst) {
+                    return;
+                }
+                depth.set(0);
+                forwarder.pushed.notified().await;
+                continue;
+            };
+            depth.set(forwarder.updates.lock().unwrap().len() as i64);
+            forwarder.popped.notify_one();
+            if orders_tx.send(update).await.is_err() {
+                forwarder.closed.store(true, Ordering::SeqCst);
+                forwarder.popped.notify_one();
+                return;
+            }
+        }
+    });
+    let ingress = Ingress {
+        queue,
+        overflows: METRICS.ingress_overflows.with_label_values(&[
+            &labels[0],
+            &labels[1],
+            &overflow.to_string(),
+        ]),
+        overloaded,
+        exchange,
+        pair: pair.to_string(),
+        overflow,
+    };
+    (ingress, handle)
+}
+
+impl Ingress {
+    // false once the book is gone
+    pub async fn send(&self, mut update: Update) -> bool {
+        loop {
+            if self.queue.closed.load(Ordering::SeqCst) {
+                return false;
+            }
+            match self.push(update) {
+                Ok(()) => return true,
+                Err(blocked) => {
+                    update = blocked;
+                    self.queue.popped.notified().await;
+                }
+            }
+        }
+    }
+
+    // queues the update or applies the overflow policy, block gives it back
+    fn push(&self, update: Update) -> Result<(), Update> {
+        let mut updates = self.queue.updates.lock().unwrap();
+        if updates.len() < self.queue.capacity {
+            updates.push_back(update);
+            let drained = updates.len() == 1;
+            drop(updates);
+            self.queue.pushed.notify_one();
+            // the forwarder caught up with everything before this one
+            if drained && self.overloaded.get() == 1 {
+                info!("{} {} ingress queue recovered", self.exchange, self.pair);
+                self.overloaded.set(0);
+            }
+            return Ok(());
+        }
+        if self.overloaded.get() == 0 {
+            warn!(
+                "{} {} ingress queue full, overflow {}",
+                self.exchange, self.pair, self.overflow
+            );
+            self.overloaded.set(1);
+        }
+        self.overflows.inc();
+        match self.overflow {
+            Overflow::Block => return Err(update),
+            Overflow::Conflate => {
+                updates.clear();
+                updates.push_back(update);
+            }
+            Overflow::Drop => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Ingress {
+    // the forwarder still delivers what is queued, like the last clearing update
+    fn drop(&mut self) {
+        self.queue.closed.store(true, Ordering::SeqCst);
+        self.queue.pushed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn update(amount: &str) -> Update {
+        let mut update = Update::new(Exchange::Binance, 8, 8);
+        update.add_bid("1", amount);
+        update
+    }
+
+    fn amount(update: Update) -> f64 {
+        let mut book = crate::book::Book::new();
+        book.add_orders(update);
+        book.to_summary().bids[0].amount
+    }
+
+    #[tokio::test]
+    async fn test_overflow() {
+        for (overflow, expected) in [
+            (Overflow::Conflate, vec![1.0, 4.0]),
+            (Overflow::Drop, vec![1.0, 2.0]),
+            (Overflow::Block, vec![1.0, 2.0, 3.0, 4.0]),
+        ] {
+            // the book takes one update and stalls with a second queued
+            let (orders_tx, mut orders_rx) = mpsc::channel(1);
+            let (ingress, forwarder) =
+                ingress(Exchange::Binance, "overflow", 1, overflow, orders_tx);
+            assert!(ingress.send(update("1")).await);
+            tokio::task::yield_now().await;
+            assert!(ingress.send(update("2")).await);
+            if overflow == Overflow::Block {
+                let book = tokio::spawn(async move {
+                    let mut amounts = Vec::new();
+                    while let Some(update) = orders_rx.recv().await {
+                        amounts.push(amount(update));
+                    }
+                    amounts
+                });
+                assert!(ingress.send(update("3")).await);
+                assert!(ingress.send(update("4")).await);
+                drop(ingress);
+                forwarder.await.unwrap();
+                assert_eq!(book.await.unwrap(), expected);
+                continue;
+            }
+            assert!(ingress.send(update("3")).await);
+            assert!(ingress.send(update("4")).await);
+            let overloaded = METRICS
+                .ingress_overloaded
+                .with_label_values(&["binance", "overflow"]);
+            assert_eq!(overloaded.get(), 1);
+            drop(ingress);
+            let mut amounts = Vec::new();
+            while let Some(update) = orders_rx.recv().await {
+                amounts.push(amount(update));
+            }
+            forwarder.await.unwrap();
+            assert_eq!(amounts, expected, "{}", overflow);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_book_gone() {
+        let (orders_tx, orders_rx) = mpsc::channel(1);
+        let (ingress, forwarder) =
+            ingress(Exchange::Binance, "gone", 4, Overflow::Block, orders_tx);
+        drop(orders_rx);
+        ingress.send(update("1")).await;
+        forwarder.await.unwrap();
+        assert!(!ingress.send(update("2")).await);
+    }
+}
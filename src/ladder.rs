@@ -0,0 +1,430 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::export::Exporter;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::ExecutableCommand;
+use futures::StreamExt;
+use infonode::sdk::{Level, LocalBook, Missed, Subscription};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::io::stdout;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const BAR_WIDTH: usize = 30;
+const REDRAW: Duration = Duration::from_millis(250);
+// no summary for this long and the book is shown as stale
+const STALE: Duration = Duration::from_secs(3);
+// how long a gap in the sequence stays on screen
+const MISSED_SHOWN: Duration = Duration::from_secs(5);
+
+// summaries per second over the last few seconds
+pub struct Rate {
+    window: Duration,
+    updates: VecDeque<Instant>,
+}
+
+impl Rate {
+    pub fn new(window: Duration) -> Rate {
+        Rate {
+            window,
+            updates: VecDeque::new(),
+        }
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        self.updates.push_back(now);
+    }
+
+    pub fn per_second(&mut self, now: Instant) -> f64 {
+        while let Some(update) = self.updates.front() {
+            if now.duration_since(*update) <= self.window {
+                break;
+            }
+            self.updates.pop_front();
+        }
+        self.updates.len() as f64 / self.window.as_secs_f64()
+    }
+}
+
+struct Ladder {
+    book: LocalBook,
+    rate: Rate,
+    last: Option<Instant>,
+    // the last gap and when it was seen
+    missed: Option<(String, Instant)>,
+}
+
+// the full screen ladder of a pair until q, esc or ctrl-c
+pub async fn run(
+    mut subscription: Subscription,
+    mut exporter: Option<Exporter>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // the subscription runs apart so that redraws and keys never cancel it
+    let (books_tx, mut books_rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let update = subscription
+                .next()
+                .await
+                .cloned()
+                .map(|book| (book, subscription.missed()));
+            if let (Ok((book, _)), Some(exporter)) = (&update, exporter.as_mut()) {
+                exporter.record(book);
+            }
+            let failed = update.is_err();
+            if books_tx.send(update).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    terminal::enable_raw_mode()?;
+    stdout().execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+    let result = draw_loop(&mut terminal, &mut books_rx).await;
+    terminal::disable_raw_mode()?;
+    stdout().execute(LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+async fn draw_loop(
+    terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>,
+    books_rx: &mut mpsc::Receiver<Result<(LocalBook, Option<Missed>), tonic::Status>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ladder = Ladder {
+        book: LocalBook::default(),
+        rate: Rate::new(Duration::from_secs(5)),
+        last: None,
+        missed: None,
+    };
+    let mut events = EventStream::new();
+    let mut redraw = tokio::time::interval(REDRAW);
+    loop {
+        tokio::select! {
+            update = books_rx.recv() => {
+                let Some(update) = update else { return Ok(()) };
+                let (book, missed) = update?;
+                let now = Instant::now();
+                ladder.rate.update(now);
+                ladder.last = Some(now);
+                match missed {
+                    Some(Missed::Updates(n)) => ladder.missed = Some((format!("missed {} updates", n), now)),
+                    Some(Missed::Unknown) => ladder.missed = Some(("updates may have been missed".to_string(), now)),
+                    None => {}
+                }
+                ladder.book = book;
+            }
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                        return Ok(());
+                    }
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+                _ => {}
+            },
+            _ = redraw.tick() => {}
+        }
+        terminal.draw(|frame| draw(frame, &mut ladder))?;
+    }
+}
+
+fn draw(frame: &mut Frame, ladder: &mut Ladder) {
+    let [header, levels, footer] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(3),
+    ])
+    .areas(frame.size());
+    let book = &ladder.book;
+    let now = Instant::now();
+
+    let text =
+        |value: Option<bigdecimal::BigDecimal>| value.map_or("-".to_string(), |v| v.to_string());
+    let age = match ladder.last {
+        Some(last) if now.duration_since(last) < STALE => Span::raw("live"),
+        Some(last) => Span::styled(
+            format!("stale {}s", now.duration_since(last).as_secs()),
+            Style::default().fg(Color::Red),
+        ),
+        None => Span::styled("connecting", Style::default().fg(Color::Yellow)),
+    };
+    let mut spans = vec![
+        Span::styled(
+            book.pair.clone(),
+            Style::default().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(format!(
+            "  spread {}  mid {}  {:.1} upd/s  seq {}  ",
+            text(book.spread()),
+            text(book.mid()),
+            ladder.rate.per_second(now),
+            book.sequence
+        )),
+        age,
+    ];
+    if let Some((_, at)) = ladder.missed {
+        if now.duration_since(at) >= MISSED_SHOWN {
+            ladder.missed = None;
+        }
+    }
+    if let Some((missed, _)) = &ladder.missed {
+        spans.push(Span::styled(
+            format!("  {}", missed),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    frame.render_widget(
+        Paragraph::new(Line::from(spans))
+            .block(Block::default().borders(Borders::ALL).title("infonode")),
+        header,
+    );
+
+    // asks above bids, the best of each next to the spread
+    let bars = depth_bars(&book.bids, &book.asks, BAR_WIDTH);
+    let prices = align(
+        book.asks
+            .iter()
+            .chain(book.bids.iter())
+            .map(|l| l.price.to_string()),
+    );
+    let amounts = align(
+        book.asks
+            .iter()
+            .chain(book.bids.iter())
+            .map(|l| l.amount.to_string()),
+    );
+    let row = |i: usize, level: &Level, bar: &str, side: Color| {
+        Row::new(vec![
+            Cell::from(level.exchange.clone())
+                .style(Style::default().fg(exchange_color(&level.exchange))),
+            Cell::from(prices[i].clone()).style(Style::default().fg(side)),
+            Cell::from(amounts[i].clone()),
+            Cell::from(bar.to_string()).style(Style::default().fg(side)),
+        ])
+    };
+    let asks = book.asks.len();
+    let mut rows: Vec<Row> = (0..asks)
+        .rev()
+        .map(|i| row(i, &book.asks[i], &bars.1[i], Color::Red))
+        .collect();
+    rows.push(
+        Row::new(vec![
+            Cell::from(""),
+            Cell::from(format!("spread {}", text(book.spread()))),
+        ])
+        .style(Style::default().add_modifier(Modifier::DIM)),
+    );
+    rows.extend(
+        book.bids
+            .iter()
+            .enumerate()
+            .map(|(i, level)| row(asks + i, level, &bars.0[i], Color::Green)),
+    );
+    let widths = [
+        Constraint::Length(10),
+        Constraint::Length(prices.iter().map(|p| p.len()).max().unwrap_or(0).max(16) as u16),
+        Constraint::Length(amounts.iter().map(|a| a.len()).max().unwrap_or(0).max(8) as u16),
+        Constraint::Length(BAR_WIDTH as u16),
+    ];
+    frame.render_widget(
+        Table::new(rows, widths)
+            .header(
+                Row::new(vec!["exchange", "price", "amount", "depth"])
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+            )
+            .block(Block::default().borders(Borders::ALL)),
+        levels,
+    );
+
+    let statuses: Vec<Span> = book
+        .exchanges
+        .values()
+        .flat_map(|status| {
+            [
+                Span::styled(
+                    status.exchange.clone(),
+                    Style::default().fg(exchange_color(&status.exchange)),
+                ),
+                Span::raw(format!(
+                    " {}  ",
+                    status.state().as_str_name().to_lowercase()
+                )),
+            ]
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(Line::from(statuses))
+            .block(Block::default().borders(Borders::ALL).title("q to quit")),
+        footer,
+    );
+}
+
+fn exchange_color(exchange: &str) -> Color {
+    match exchange {
+        "binance" => Color::Yellow,
+        "bitstamp" => Color::Cyan,
+        _ => Color::Magenta,
+    }
+}
+
+// cumulative amount from the best level, scaled to the deeper side
+pub fn depth_bars(bids: &[Level], asks: &[Level], width: usize) -> (Vec<String>, Vec<String>) {
+    let cumulative = |levels: &[Level]| {
+        levels
+            .iter()
+            .scan(0.0, |total, level| {
+                *total += level.amount.to_string().parse::<f64>().unwrap_or(0.0);
+                Some(*total)
+            })
+            .collect::<Vec<f64>>()
+    };
+    let (bids, asks) = (cumulative(bids), cumulative(asks));
+    let deepest = bids.iter().chain(asks.iter()).cloned().fold(0.0, f64::max);
+    let bar = |total: &f64| {
+        if deepest > 0.0 {
+            "█".repeat(((total / deepest) * width as f64).round() as usize)
+        } else {
+            String::new()
+        }
+    };
+    (
+        bids.iter().map(bar).collect(),
+        asks.iter().map(bar).collect(),
+    )
+}
+
+// pads the decimals so that the points line up
+pub fn align(values: impl Iterator<Item = String>) -> Vec<String> {
+    let values: Vec<String> = values.collect();
+    let split = |value: &String| match value.find('.') {
+        Some(point) => (point, value.len() - point),
+        None => (value.len(), 0),
+    };
+    let integer = values.iter().map(|v| split(v).0).max().unwrap_or(0);
+    let fraction = values.iter().map(|v| split(v).1).max().unwrap_or(0);
+    values
+        .iter()
+        .map(|value| {
+            let (i, f) = split(value);
+            format!(
+                "{}{}{}",
+                " ".repeat(integer - i),
+                value,
+                " ".repeat(fraction - f)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigdecimal::BigDecimal;
+    use std::str::FromStr;
+
+    fn level(amount: &str) -> Level {
+        Level {
+            exchange: "binance".to_string(),
+            price: BigDecimal::from(1),
+            amount: BigDecimal::from_str(amount).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_depth_bars() {
+        let (bids, asks) = depth_bars(&[level("1"), level("3")], &[level("2")], 8);
+        assert_eq!(bids, vec!["██", "████████"]);
+        assert_eq!(asks, vec!["████"]);
+        let (bids, asks) = depth_bars(&[], &[], 8);
+        assert!(bids.is_empty() && asks.is_empty());
+    }
+
+    #[test]
+    fn test_align() {
+        let values = ["0.0651", "0.06512", "12.5", "3"].map(String::from);
+        assert_eq!(
+            align(values.into_iter()),
+            vec![" 0.0651 ", " 0.06512", "12.5    ", " 3      "]
+        );
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut ladder = Ladder {
+            book: LocalBook {
+                pair: "ethbtc".to_string(),
+                bids: vec![level("1.5")],
+                asks: vec![level("2"), level("0.25")],
+                ..LocalBook::default()
+            },
+            rate: Rate::new(Duration::from_secs(5)),
+            last: Some(Instant::now()),
+            missed: Some(("missed 2 updates".to_string(), Instant::now())),
+        };
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(100, 12)).unwrap();
+        let mut screen = |ladder: &mut Ladder| -> String {
+            terminal.draw(|frame| draw(frame, ladder)).unwrap();
+            terminal
+                .backend()
+                .buffer()
+                .content
+                .iter()
+                .map(|c| c.symbol())
+                .collect()
+        };
+        let shown = screen(&mut ladder);
+        assert!(shown.contains("ethbtc"));
+        assert!(shown.contains("missed 2 updates"));
+        assert!(shown.contains("0.25"));
+
+        // an old gap goes away
+        ladder.missed = Some((
+            "missed 2 updates".to_string(),
+            Instant::now() - MISSED_SHOWN,
+        ));
+        assert!(!screen(&mut ladder).contains("missed"));
+        assert!(ladder.missed.is_none());
+    }
+
+    #[test]
+    fn test_rate() {
+        let start = Instant::now();
+        let mut rate = Rate::new(Duration::from_secs(2));
+        for i in 0..4 {
+            rate.update(start + Duration::from_millis(500 * i));
+        }
+        assert_eq!(rate.per_second(start + Duration::from_millis(1500)), 2.0);
+        assert_eq!(rate.per_second(start + Duration::from_secs(3)), 1.0);
+    }
+}
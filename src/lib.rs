@@ -0,0 +1,67 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+pub mod auth;
+
+pub mod book;
+
+pub mod binance;
+
+pub mod bitstamp;
+
+pub mod config;
+
+pub mod endpoints;
+
+pub mod gateway;
+
+pub mod ingress;
+
+pub mod metrics;
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+pub mod pipeline;
+
+pub mod recorder;
+
+pub mod replay;
+
+pub mod rest;
+
+pub mod sdk;
+
+pub mod service;
+
+pub mod status;
+
+pub mod supervisor;
+
+pub mod tls;
+
+pub mod orderbook {
+    tonic::include_proto!("orderbook");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("orderbook_descriptor");
+}
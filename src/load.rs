@@ -0,0 +1,218 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use infonode::sdk::ClientConfig;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+// what one BookSummary stream received during the run
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    pub messages: u64,
+    // between consecutive summaries, in microseconds
+    pub gaps_us: Vec<u64>,
+    // from the server stamp to the receive, in microseconds
+    pub latencies_us: Vec<u64>,
+    pub error: Option<String>,
+}
+
+impl StreamStats {
+    fn receive(&mut self, now: SystemTime, previous: Option<SystemTime>, sent_us: Option<u64>) {
+        self.messages += 1;
+        if let Some(gap) = previous.and_then(|previous| now.duration_since(previous).ok()) {
+            self.gaps_us.push(gap.as_micros() as u64);
+        }
+        if let Some(sent_us) = sent_us.filter(|sent| *sent > 0) {
+            // clocks of other hosts may be behind
+            self.latencies_us.push(unix_us(now).saturating_sub(sent_us));
+        }
+    }
+}
+
+// opens the streams together and reports once they all ran for the duration
+pub async fn run(
+    config: ClientConfig,
+    pair: String,
+    streams: usize,
+    duration: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let deadline = tokio::time::Instant::now() + duration;
+    let mut tasks = JoinSet::new();
+    for _ in 0..streams {
+        let (config, pair) = (config.clone(), pair.clone());
+        tasks.spawn(async move {
+            let mut stats = StreamStats::default();
+            if let Err(e) = stream(&config, &pair, deadline, &mut stats).await {
+                stats.error = Some(e);
+            }
+            stats
+        });
+    }
+    let mut all = Vec::with_capacity(streams);
+    while let Some(stats) = tasks.join_next().await {
+        all.push(stats?);
+    }
+    println!("{}", report(&all, duration));
+    Ok(())
+}
+
+async fn stream(
+    config: &ClientConfig,
+    pair: &str,
+    deadline: tokio::time::Instant,
+    stats: &mut StreamStats,
+) -> Result<(), String> {
+    let mut client = config.connect().await?;
+    let mut summaries = client.summaries(pair).await.map_err(|s| s.to_string())?;
+    let mut previous = None;
+    loop {
+        let summary = tokio::select! {
+            summary = summaries.message() => summary.map_err(|s| s.to_string())?,
+            _ = tokio::time::sleep_until(deadline) => return Ok(()),
+        };
+        let Some(summary) = summary else {
+            return Err("summaries ended".to_string());
+        };
+        let now = SystemTime::now();
+        stats.receive(now, previous, summary.latency.map(|l| l.sent_us));
+        previous = Some(now);
+    }
+}
+
+pub fn report(all: &[StreamStats], duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    // in hundredths, slow streams get a few messages a second
+    let mut rates: Vec<u64> = all
+        .iter()
+        .map(|s| (s.messages as f64 * 100.0 / secs).round() as u64)
+        .collect();
+    let mut gaps: Vec<u64> = all.iter().flat_map(|s| s.gaps_us.iter().cloned()).collect();
+    // how much each gap differs from the one before it on the same stream
+    let mut jitter: Vec<u64> = all
+        .iter()
+        .flat_map(|s| s.gaps_us.windows(2).map(|w| w[0].abs_diff(w[1])))
+        .collect();
+    let mut latencies: Vec<u64> = all
+        .iter()
+        .flat_map(|s| s.latencies_us.iter().cloned())
+        .collect();
+    let total: u64 = all.iter().map(|s| s.messages).sum();
+    let mut lines = vec![format!(
+        "{} streams for {:.0}s, {} summaries, {:.1} msg/s",
+        all.len(),
+        secs,
+        total,
+        total as f64 / secs
+    )];
+    lines.push(format!(
+        "rate msg/s per stream {}",
+        percentiles(&mut rates, 100)
+    ));
+    lines.push(format!("inter-arrival ms {}", percentiles(&mut gaps, 1000)));
+    lines.push(format!("jitter ms {}", percentiles(&mut jitter, 1000)));
+    if latencies.is_empty() {
+        lines.push("latency: no server stamps, enable summary_latency on the server".to_string());
+    } else {
+        lines.push(format!("latency ms {}", percentiles(&mut latencies, 1000)));
+    }
+    let failed: Vec<&String> = all.iter().filter_map(|s| s.error.as_ref()).collect();
+    if let Some(first) = failed.first() {
+        lines.push(format!("{} streams failed, first: {}", failed.len(), first));
+    }
+    lines.join("\n")
+}
+
+// the values are divided by a power of ten scale, so microseconds print as
+// milliseconds with three decimals
+fn percentiles(values: &mut [u64], scale: u64) -> String {
+    if values.is_empty() {
+        return "-".to_string();
+    }
+    values.sort_unstable();
+    let decimals = scale.ilog10() as usize;
+    let format = |value: u64| format!("{:.*}", decimals, value as f64 / scale as f64);
+    let mut columns: Vec<String> = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)]
+        .iter()
+        .map(|(name, p)| format!("{} {}", name, format(percentile(values, *p))))
+        .collect();
+    columns.insert(0, format!("min {}", format(values[0])));
+    columns.push(format!("max {}", format(values[values.len() - 1])));
+    columns.join(" ")
+}
+
+// nearest rank of sorted values
+pub fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn unix_us(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&values, 0.5), 50);
+        assert_eq!(percentile(&values, 0.99), 99);
+        assert_eq!(percentile(&values, 0.999), 100);
+        assert_eq!(percentile(&[7], 0.0), 7);
+    }
+
+    #[test]
+    fn test_report() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut stats = StreamStats::default();
+        let mut previous = None;
+        for (ms, sent_us) in [(0, 0), (100, 1_000_099_000), (300, 1_000_298_500), (400, 0)] {
+            let now = start + Duration::from_millis(ms);
+            stats.receive(now, previous, Some(sent_us));
+            previous = Some(now);
+        }
+        assert_eq!(stats.gaps_us, vec![100_000, 200_000, 100_000]);
+        assert_eq!(stats.latencies_us, vec![1_000, 1_500]);
+
+        let failed = StreamStats {
+            error: Some("unavailable".to_string()),
+            ..StreamStats::default()
+        };
+        let report = report(&[stats, failed], Duration::from_secs(3));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "2 streams for 3s, 4 summaries, 1.3 msg/s");
+        assert_eq!(
+            lines[1],
+            "rate msg/s per stream min 0.00 p50 0.00 p90 1.33 p99 1.33 p99.9 1.33 max 1.33"
+        );
+        assert_eq!(
+            lines[3],
+            "jitter ms min 100.000 p50 100.000 p90 100.000 p99 100.000 p99.9 100.000 max 100.000"
+        );
+        assert!(lines[4].starts_with("latency ms min 1.000 p50 1.000"));
+        assert_eq!(lines[5], "1 streams failed, first: unavailable");
+    }
+}
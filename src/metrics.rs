@@ -0,0 +1,299 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::info;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::watch;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+pub struct Metrics {
+    registry: Registry,
+    // exchange, pair
+    pub messages: IntCounterVec,
+    pub parse_errors: IntCounterVec,
+    pub reconnects: IntCounterVec,
+    pub connector_up: IntGaugeVec,
+    pub connector_restarts: IntCounterVec,
+    // exchange, pair, cause
+    pub connector_failures: IntCounterVec,
+    // exchange, pair, stage
+    pub stage_latency: HistogramVec,
+    // pair
+    pub book_update: HistogramVec,
+    pub spread: GaugeVec,
+    pub mid: GaugeVec,
+    // pair, stream
+    pub clients: IntGaugeVec,
+    pub dropped_clients: IntCounterVec,
+    pub slow_sends: IntCounterVec,
+    // pair, queue
+    pub queue_depth: IntGaugeVec,
+    // exchange, pair, policy
+    pub ingress_overflows: IntCounterVec,
+    // exchange, pair
+    pub ingress_overloaded: IntGaugeVec,
+    // cause
+    pub tls_handshake_failures: IntCounterVec,
+    // exchange, pair
+    pub recorder_dropped: IntCounterVec,
+}
+
+// the series of one grpc stream kind of a pair
+pub struct StreamMetrics {
+    pub clients: IntGauge,
+    pub dropped: IntCounter,
+    pub slow: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            IntCounterVec::new(Opts::new(name, help).namespace("infonode"), labels).unwrap()
+        };
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            IntGaugeVec::new(Opts::new(name, help).namespace("infonode"), labels).unwrap()
+        };
+        let metrics = Metrics {
+            registry: Registry::new(),
+            messages: counter(
+                "messages_total",
+                "websocket frames received",
+                &["exchange", "pair"],
+            ),
+            parse_errors: counter(
+                "parse_errors_total",
+                "frames that did not parse into a book update",
+                &["exchange", "pair"],
+            ),
+            reconnects: counter(
+                "reconnects_total",
+                "connector reconnections",
+                &["exchange", "pair"],
+            ),
+            connector_up: gauge(
+                "connector_up",
+                "1 while the connector task runs",
+                &["exchange", "pair"],
+            ),
+            connector_restarts: counter(
+                "connector_restarts_total",
+                "connector tasks restarted by their supervisor",
+                &["exchange", "pair"],
+            ),
+            connector_failures: counter(
+                "connector_failures_total",
+                "connector tasks that panicked or exited on their own",
+                &["exchange", "pair", "cause"],
+            ),
+            stage_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "stage_latency_seconds",
+                    "latency of each pipeline stage: exchange, parse, queue, book, fanout",
+                )
+                .namespace("infonode")
+                .buckets(prometheus::exponential_buckets(1e-6, 2.0, 23).unwrap()),
+                &["exchange", "pair", "stage"],
+            )
+            .unwrap(),
+            book_update: HistogramVec::new(
+                HistogramOpts::new("book_update_seconds", "time spent in Book::add_orders")
+                    .namespace("infonode")
+                    .buckets(prometheus::exponential_buckets(1e-6, 2.0, 16).unwrap()),
+                &["pair"],
+            )
+            .unwrap(),
+            spread: GaugeVec::new(
+                Opts::new("spread", "best ask minus best bid").namespace("infonode"),
+                &["pair"],
+            )
+            .unwrap(),
+            mid: GaugeVec::new(
+                Opts::new("mid_price", "mean of best bid and best ask").namespace("infonode"),
+                &["pair"],
+            )
+            .unwrap(),
+            clients: gauge(
+                "grpc_clients",
+                "connected grpc stream clients",
+                &["pair", "stream"],
+            ),
+            dropped_clients: counter(
+                "grpc_dropped_clients_total",
+                "grpc clients removed once gone, or events clients with a full queue",
+                &["pair", "stream"],
+            ),
+            slow_sends: counter(
+                "grpc_slow_sends_total",
+                "summaries skipped because the client queue was full",
+                &["pair", "stream"],
+            ),
+            queue_depth: gauge(
+                "queue_depth",
+                "messages waiting in the book loop and ingress queues",
+                &["pair", "queue"],
+            ),
+            ingress_overflows: counter(
+                "ingress_overflows_total",
+                "updates that found the ingress queue of their connector full",
+                &["exchange", "pair", "policy"],
+            ),
+            ingress_overloaded: gauge(
+                "ingress_overloaded",
+                "1 from an ingress overflow until the queue drains",
+                &["exchange", "pair"],
+            ),
+            tls_handshake_failures: counter(
+                "tls_handshake_failures_total",
+                "tls connections dropped by a failed or timed out handshake",
+                &["cause"],
+            ),
+            recorder_dropped: counter(
+                "recorder_dropped_total",
+                "frames not recorded because the capture writer fell behind",
+                &["exchange", "pair"],
+            ),
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 18] = [
+            Box::new(metrics.messages.clone()),
+            Box::new(metrics.parse_errors.clone()),
+            Box::new(metrics.reconnects.clone()),
+            Box::new(metrics.connector_up.clone()),
+            Box::new(metrics.connector_restarts.clone()),
+            Box::new(metrics.connector_failures.clone()),
+            Box::new(metrics.stage_latency.clone()),
+            Box::new(metrics.book_update.clone()),
+            Box::new(metrics.spread.clone()),
+            Box::new(metrics.mid.clone()),
+            Box::new(metrics.clients.clone()),
+            Box::new(metrics.dropped_clients.clone()),
+            Box::new(metrics.slow_sends.clone()),
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.ingress_overflows.clone()),
+            Box::new(metrics.ingress_overloaded.clone()),
+            Box::new(metrics.tls_handshake_failures.clone()),
+            Box::new(metrics.recorder_dropped.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn stream(&self, pair: &str, stream: &str) -> StreamMetrics {
+        StreamMetrics {
+            clients: self.clients.with_label_values(&[pair, stream]),
+            dropped: self.dropped_clients.with_label_values(&[pair, stream]),
+            slow: self.slow_sends.with_label_values(&[pair, stream]),
+        }
+    }
+
+    pub fn observe_stage(&self, exchange: &str, pair: &str, stage: &str, latency: Duration) {
+        self.stage_latency
+            .with_label_values(&[exchange, pair, stage])
+            .observe(latency.as_secs_f64());
+    }
+
+    // prometheus text exposition format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(
+                hyper::header::CONTENT_TYPE,
+                TextEncoder::new().format_type(),
+            )
+            .body(Body::from(METRICS.encode())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+// serve /metrics over http until the shutdown
+pub async fn serve(addr: SocketAddr, mut shutdown: watch::Receiver<()>) -> Result<(), String> {
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format!("cannot bind metrics on {}: {}", addr, e))?
+        .serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(handle))
+        }));
+    info!(
+        "metrics listening on http://{}/metrics",
+        server.local_addr()
+    );
+    server
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.changed().await;
+        })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics() {
+        METRICS
+            .messages
+            .with_label_values(&["binance", "metricspair"])
+            .inc();
+        METRICS.stream("metricspair", "summary").clients.set(3);
+
+        let response = handle(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            text.contains(r#"infonode_messages_total{exchange="binance",pair="metricspair"} 1"#)
+        );
+        assert!(text.contains(r#"infonode_grpc_clients{pair="metricspair",stream="summary"} 3"#));
+
+        let response = handle(Request::get("/other").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,218 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::book::Exchange;
+use crate::endpoints::Endpoints;
+use log::{debug, info};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tungstenite::{accept, Message};
+
+// one scripted websocket session, a new connection plays the next one
+pub enum Step {
+    // text frame sent as is, malformed frames included
+    Send(String),
+    // wait for a bts:subscribe and acknowledge the requested channel
+    Subscribe,
+    Wait(Duration),
+    // send the frame at every interval until the connector goes away
+    Repeat(String, Duration),
+    // drop the connection without a close handshake
+    Disconnect,
+}
+
+pub struct MockExchange {
+    exchange: Exchange,
+    rest_url: String,
+    websocket_url: String,
+}
+
+impl MockExchange {
+    pub fn new(
+        exchange: Exchange,
+        pair: &str,
+        precisions: (u64, u64),
+        sessions: Vec<Vec<Step>>,
+    ) -> MockExchange {
+        let rest = TcpListener::bind("127.0.0.1:0").unwrap();
+        let websocket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mock = MockExchange {
+            exchange: exchange.clone(),
+            rest_url: format!("http://{}", rest.local_addr().unwrap()),
+            websocket_url: format!("ws://{}", websocket.local_addr().unwrap()),
+        };
+
+        let body = precisions_body(&exchange, pair, precisions);
+        thread::spawn(move || {
+            for stream in rest.incoming().flatten() {
+                serve_rest(stream, &body);
+            }
+        });
+
+        let sessions = Arc::new(Mutex::new(VecDeque::from(sessions)));
+        thread::spawn(move || {
+            for stream in websocket.incoming().flatten() {
+                let session = sessions.lock().unwrap().pop_front();
+                thread::spawn(move || serve_websocket(stream, session));
+            }
+        });
+
+        info!(
+            "mock {} rest={} websocket={}",
+            mock.exchange, mock.rest_url, mock.websocket_url
+        );
+        mock
+    }
+
+    pub fn exchange(&self) -> Exchange {
+        self.exchange.clone()
+    }
+
+    pub fn endpoints(&self) -> Endpoints {
+        let mut endpoints = Endpoints::new(&self.exchange);
+        endpoints.set_rest(&self.rest_url).unwrap();
+        endpoints.set_websocket(&self.websocket_url).unwrap();
+        endpoints
+    }
+}
+
+pub fn binance_depth(bids: &[(&str, &str)], asks: &[(&str, &str)]) -> String {
+    format!(
+        r#"{{"lastUpdateId":1,"bids":[{}],"asks":[{}]}}"#,
+        levels(bids),
+        levels(asks)
+    )
+}
+
+pub fn bitstamp_depth(pair: &str, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> String {
+    format!(
+        r#"{{"event":"data","channel":"order_book_{}","data":{{"microtimestamp":"{}","bids":[{}],"asks":[{}]}}}}"#,
+        pair,
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros(),
+        levels(bids),
+        levels(asks)
+    )
+}
+
+pub fn bitstamp_event(event: &str, data: json::JsonValue) -> String {
+    json::object! { event: event, channel: "", data: data }.dump()
+}
+
+fn levels(levels: &[(&str, &str)]) -> String {
+    levels
+        .iter()
+        .map(|(price, amount)| format!(r#"["{}","{}"]"#, price, amount))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn precisions_body(exchange: &Exchange, pair: &str, (price, amount): (u64, u64)) -> String {
+    match exchange {
+        Exchange::Binance => format!(
+            r#"{{"symbols":[{{"symbol":"{}","quotePrecision":{},"baseAssetPrecision":{}}}]}}"#,
+            pair.to_ascii_uppercase(),
+            price,
+            amount
+        ),
+        Exchange::Bitstamp => format!(
+            r#"[{{"url_symbol":"{}","base_decimals":{},"instant_order_counter_decimals":{}}}]"#,
+            pair, price, amount
+        ),
+    }
+}
+
+fn serve_rest(stream: TcpStream, body: &str) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok() && line != "\r\n" && !line.is_empty() {
+        debug!("mock rest {}", line.trim_end());
+        line.clear();
+    }
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = (&stream).write_all(response.as_bytes());
+}
+
+fn serve_websocket(stream: TcpStream, session: Option<Vec<Step>>) {
+    let Ok(mut socket) = accept(stream) else {
+        return;
+    };
+    // no script left: refuse the session
+    let Some(steps) = session else {
+        let _ = socket.close(None);
+        return;
+    };
+    for step in steps {
+        let sent = match step {
+            Step::Send(frame) => socket.write_message(Message::Text(frame)).is_ok(),
+            Step::Subscribe => match socket.read_message() {
+                Ok(msg) => {
+                    let parsed = json::parse(&msg.to_string()).unwrap_or(json::Null);
+                    let ack = json::object! {
+                        event: "bts:subscription_succeeded",
+                        channel: parsed["data"]["channel"].clone(),
+                        data: json::object! {},
+                    };
+                    socket.write_message(Message::Text(ack.dump())).is_ok()
+                }
+                Err(_) => false,
+            },
+            Step::Wait(duration) => {
+                thread::sleep(duration);
+                true
+            }
+            Step::Repeat(frame, interval) => {
+                while socket.write_message(Message::Text(frame.clone())).is_ok() {
+                    thread::sleep(interval);
+                }
+                false
+            }
+            Step::Disconnect => return,
+        };
+        if !sent {
+            return;
+        }
+    }
+    // keep the session open until the connector goes away, answering heartbeats
+    while let Ok(msg) = socket.read_message() {
+        if json::parse(&msg.to_string()).unwrap_or(json::Null)["event"] == "bts:heartbeat" {
+            let reply = json::object! {
+                event: "bts:heartbeat",
+                channel: "",
+                data: json::object! { status: "success" },
+            };
+            if socket.write_message(Message::Text(reply.dump())).is_err() {
+                return;
+            }
+        }
+    }
+}
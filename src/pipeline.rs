@@ -0,0 +1,797 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::auth::{restrict, Entitlements};
+use crate::binance::BinanceClient;
+use crate::bitstamp::BitstampClient;
+use crate::book::{Book, Exchange, Timing, Update};
+use crate::config::Config;
+use crate::endpoints::Endpoints;
+use crate::ingress::{Ingress, Overflow};
+use crate::metrics::{StreamMetrics, METRICS};
+use crate::orderbook::{ExchangeStatus, Instrument, Latency, Precision, Summary};
+use crate::recorder::Recorder;
+use crate::replay::ReplayClient;
+use crate::status::{StatusReporter, StopSignal};
+use crate::supervisor::Supervisor;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tonic::Status;
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// updates waiting for the book, short so that a slow book fills the ingress
+// queues of the connectors where their overflow policy applies
+const ORDERS_CAPACITY: usize = 16;
+
+pub type SummarySender = Sender<Result<Summary, Status>>;
+pub type SummaryReceiver = Receiver<Result<Summary, Status>>;
+pub type StatusSender = Sender<Result<ExchangeStatus, Status>>;
+pub type SnapshotSender = oneshot::Sender<Summary>;
+
+pub enum Command {
+    Depth(usize),
+    SummaryLatency(bool),
+    Fee(Exchange, f64),
+    // send a last summary, end the streams with the status and stop the loop
+    Close(Status),
+}
+
+#[derive(Debug)]
+pub struct PairChannels {
+    pub clients_tx: UnboundedSender<SummarySender>,
+    pub event_clients_tx: UnboundedSender<StatusSender>,
+    // one summary for callers that don't follow the stream
+    pub snapshots_tx: UnboundedSender<SnapshotSender>,
+    // unix time in ms of the last update with levels, 0 before the first
+    pub last_book: Arc<AtomicU64>,
+    // price and amount precisions per exchange, as last seen by the book
+    pub precisions: Arc<RwLock<BTreeMap<String, (u64, u64)>>>,
+}
+
+// what the grpc service sees of the running pairs
+#[derive(Debug, Default)]
+pub struct Registry {
+    pub pairs: BTreeMap<String, PairChannels>,
+    pub default_pair: String,
+    pub event_clients: Vec<StatusSender>,
+    pub closing: bool,
+}
+
+impl Registry {
+    // every pair got a book from at least one exchange within stale_after
+    pub fn fresh(&self, stale_after: Duration) -> bool {
+        let now = unix_ms(SystemTime::now());
+        !self.closing
+            && !self.pairs.is_empty()
+            && self.pairs.values().all(|channels| {
+                let last = channels.last_book.load(Ordering::Relaxed);
+                last > 0 && now.saturating_sub(last) <= stale_after.as_millis() as u64
+            })
+    }
+
+    // the pairs and exchange precisions a client may see
+    pub fn instruments(&self, entitlements: &Entitlements) -> Vec<Instrument> {
+        self.pairs
+            .iter()
+            .filter(|(pair, _)| entitlements.allows_pair(pair))
+            .map(|(pair, channels)| Instrument {
+                pair: pair.clone(),
+                default: *pair == self.default_pair,
+                precisions: channels
+                    .precisions
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .filter(|(exchange, _)| entitlements.allows_exchange(exchange))
+                    .map(|(exchange, (price, amount))| Precision {
+                        exchange: exchange.clone(),
+                        price: *price,
+                        amount: *amount,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    // summary stream of a pair, the default pair when empty, as entitled
+    #[allow(clippy::result_large_err)]
+    pub fn summaries(
+        &self,
+        pair: &str,
+        entitlements: &Entitlements,
+    ) -> Result<(String, SummaryReceiver), Status> {
+        let (pair, channels) = self.channels(pair, entitlements)?;
+        let (tx, mut rx) = mpsc::channel(100);
+        channels
+            .clients_tx
+            .send(tx)
+            .map_err(|_| Status::unavailable(format!("pair {} closed", pair)))?;
+        if entitlements.restricts_summaries() {
+            let entitlements = entitlements.clone();
+            rx = restrict(rx, entitlements.max_rate, move |summary| {
+                Some(entitlements.filter_summary(summary))
+            });
+        }
+        Ok((pair, rx))
+    }
+
+    // the current summary of a pair without joining its stream, left for
+    // the caller to filter
+    #[allow(clippy::result_large_err)]
+    pub fn snapshot(
+        &self,
+        pair: &str,
+        entitlements: &Entitlements,
+    ) -> Result<(String, oneshot::Receiver<Summary>), Status> {
+        let (pair, channels) = self.channels(pair, entitlements)?;
+        let (tx, rx) = oneshot::channel();
+        channels
+            .snapshots_tx
+            .send(tx)
+            .map_err(|_| Status::unavailable(format!("pair {} closed", pair)))?;
+        Ok((pair, rx))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn channels(
+        &self,
+        pair: &str,
+        entitlements: &Entitlements,
+    ) -> Result<(String, &PairChannels), Status> {
+        if self.closing {
+            return Err(Status::unavailable("server shutting down"));
+        }
+        let pair = match pair {
+            "" => self.default_pair.clone(),
+            pair => pair.to_string(),
+        };
+        if !entitlements.allows_pair(&pair) {
+            return Err(Status::permission_denied(format!(
+                "pair {} not allowed",
+                pair
+            )));
+        }
+        let channels = self
+            .pairs
+            .get(&pair)
+            .ok_or_else(|| Status::not_found(format!("unknown pair {}", pair)))?;
+        Ok((pair, channels))
+    }
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(PartialEq, Clone)]
+struct Settings {
+    endpoints: Endpoints,
+    record: bool,
+    queue: usize,
+    overflow: Overflow,
+}
+
+struct Connector {
+    settings: Settings,
+    stop: StopSignal,
+    handle: JoinHandle<()>,
+}
+
+// queues, book loop and exchange connectors of a single pair
+struct Pipeline {
+    pair: String,
+    depth: usize,
+    summary_latency: bool,
+    orders_tx: Sender<Update>,
+    events_tx: UnboundedSender<ExchangeStatus>,
+    commands_tx: UnboundedSender<Command>,
+    fees: BTreeMap<Exchange, f64>,
+    precisions: Arc<RwLock<BTreeMap<String, (u64, u64)>>>,
+    connectors: BTreeMap<Exchange, Connector>,
+}
+
+pub struct Pipelines {
+    config: Config,
+    pipelines: BTreeMap<String, Pipeline>,
+    registry: Arc<RwLock<Registry>>,
+}
+
+impl Pipelines {
+    pub fn start(config: Config) -> Result<Pipelines, String> {
+        let mut pipelines = Pipelines {
+            config: config.clone(),
+            pipelines: BTreeMap::new(),
+            registry: Arc::default(),
+        };
+        for pair in &config.pairs {
+            pipelines.add(pair)?;
+        }
+        pipelines.registry.write().unwrap().default_pair = config.pairs[0].clone();
+        Ok(pipelines)
+    }
+
+    pub fn registry(&self) -> Arc<RwLock<Registry>> {
+        self.registry.clone()
+    }
+
+    // apply a new configuration without dropping the grpc clients
+    pub fn reload(&mut self, mut config: Config) {
+        if config.listen != self.config.listen
+            || config.metrics != self.config.metrics
+            || config.gateway != self.config.gateway
+            || config.rest != self.config.rest
+            || config.grpc_web != self.config.grpc_web
+        {
+            warn!("listen addresses and grpc-web changes need a restart");
+        }
+        if config.replay != self.config.replay {
+            warn!("replay changes need a restart");
+            config.replay = self.config.replay.clone();
+        }
+        if let Ok(level) = config.log_level() {
+            log::set_max_level(level.to_level_filter());
+        }
+        let old = std::mem::replace(&mut self.config, config);
+
+        let removed: Vec<String> = old
+            .pairs
+            .into_iter()
+            .filter(|p| !self.config.pairs.contains(p))
+            .collect();
+        for pair in &removed {
+            info!("remove pair {}", pair);
+            self.remove(pair, Status::unavailable(format!("pair {} removed", pair)));
+        }
+        for pair in self.config.pairs.clone() {
+            match self.pipelines.get_mut(&pair) {
+                Some(pipeline) => pipeline.update(&self.config),
+                None => {
+                    info!("add pair {}", pair);
+                    if let Err(e) = self.add(&pair) {
+                        warn!("cannot add pair {}: {}", pair, e);
+                    }
+                }
+            }
+        }
+        self.registry.write().unwrap().default_pair = self.config.pairs[0].clone();
+    }
+
+    // end every stream with the status and close the exchange websockets
+    pub async fn close(&mut self, status: Status) {
+        let mut handles = Vec::new();
+        self.registry.write().unwrap().closing = true;
+        for pair in self.pipelines.keys().cloned().collect::<Vec<_>>() {
+            handles.extend(self.remove(&pair, status.clone()));
+        }
+        for tx in self.registry.write().unwrap().event_clients.drain(..) {
+            let _ = tx.try_send(Err(status.clone()));
+        }
+        // connectors wait on the stop next to their socket and close right away
+        for handle in handles {
+            if tokio::time::timeout(CLOSE_TIMEOUT, handle).await.is_err() {
+                warn!("connector still running after {:?}", CLOSE_TIMEOUT);
+            }
+        }
+    }
+
+    fn add(&mut self, pair: &str) -> Result<(), String> {
+        let (pipeline, channels) = Pipeline::start(&self.config, pair)?;
+        let mut registry = self.registry.write().unwrap();
+        // clients of the events stream follow every pair
+        registry.event_clients.retain(|tx| !tx.is_closed());
+        for tx in &registry.event_clients {
+            let _ = channels.event_clients_tx.send(tx.clone());
+        }
+        registry.pairs.insert(pair.to_string(), channels);
+        self.pipelines.insert(pair.to_string(), pipeline);
+        Ok(())
+    }
+
+    fn remove(&mut self, pair: &str, status: Status) -> Vec<JoinHandle<()>> {
+        self.registry.write().unwrap().pairs.remove(pair);
+        let Some(pipeline) = self.pipelines.remove(pair) else {
+            return Vec::new();
+        };
+        let _ = pipeline.commands_tx.send(Command::Close(status));
+        pipeline
+            .connectors
+            .into_values()
+            .map(|connector| {
+                connector.stop.stop();
+                connector.handle
+            })
+            .collect()
+    }
+}
+
+impl Pipeline {
+    fn start(config: &Config, pair: &str) -> Result<(Pipeline, PairChannels), String> {
+        // create queues
+        let (orders_tx, orders_rx) = mpsc::channel(ORDERS_CAPACITY);
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+
+        // main event loop
+        let channels = spawn_book_loop(
+            pair.to_string(),
+            config.depth,
+            config.summary_latency,
+            orders_rx,
+            events_rx,
+            commands_rx,
+        );
+
+        let mut pipeline = Pipeline {
+            pair: pair.to_string(),
+            depth: config.depth,
+            summary_latency: config.summary_latency,
+            orders_tx,
+            events_tx,
+            commands_tx,
+            fees: BTreeMap::new(),
+            precisions: channels.precisions.clone(),
+            connectors: BTreeMap::new(),
+        };
+
+        // fees reach the book before any update, connectors only start
+        // without a replay
+        pipeline.update(config);
+        if let (Some(replay), Some(mode)) = (&config.replay, config.replay_mode()?) {
+            // replay recorded captures instead of connecting to the exchanges
+            let mut replay_client = ReplayClient::new(pair.to_string(), mode);
+            for exchange in config.enabled() {
+                let files = ReplayClient::captures(&replay.dir, &exchange, pair)
+                    .map_err(|e| format!("cannot list captures: {}", e))?;
+                replay_client.add_capture(exchange, files);
+            }
+            replay_client.do_main_loop(pipeline.orders_tx.clone());
+        }
+
+        Ok((pipeline, channels))
+    }
+
+    // start, restart or stop the connectors whose settings changed
+    fn update(&mut self, config: &Config) {
+        if config.depth != self.depth {
+            self.depth = config.depth;
+            let _ = self.commands_tx.send(Command::Depth(config.depth));
+        }
+        if config.summary_latency != self.summary_latency {
+            self.summary_latency = config.summary_latency;
+            let _ = self
+                .commands_tx
+                .send(Command::SummaryLatency(config.summary_latency));
+        }
+        for exchange in [Exchange::Binance, Exchange::Bitstamp] {
+            let fee_bps = config.exchanges.get(&exchange).fee_bps;
+            if self.fees.get(&exchange) != Some(&fee_bps) {
+                self.fees.insert(exchange.clone(), fee_bps);
+                let _ = self.commands_tx.send(Command::Fee(exchange, fee_bps));
+            }
+        }
+        if config.replay.is_some() {
+            return;
+        }
+        for exchange in [Exchange::Binance, Exchange::Bitstamp] {
+            let settings = match config.endpoints(&exchange) {
+                Ok(endpoints) if config.exchanges.get(&exchange).enabled => Some(Settings {
+                    endpoints,
+                    record: config.records(&exchange, &self.pair),
+                    queue: config.exchanges.get(&exchange).queue,
+                    overflow: config.exchanges.get(&exchange).overflow,
+                }),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("{} {}: {}", exchange, self.pair, e);
+                    None
+                }
+            };
+            let previous = self.connectors.remove(&exchange);
+            if let (Some(running), Some(wanted)) = (&previous, &settings) {
+                if running.settings == *wanted {
+                    self.connectors.insert(exchange, previous.unwrap());
+                    continue;
+                }
+            }
+            // the new connector waits for the old one to clear its levels
+            let previous = previous.map(|running| {
+                info!("stop {} {}", exchange, self.pair);
+                running.stop.stop();
+                running.handle
+            });
+            if let Some(settings) = settings {
+                let connector = self.spawn_connector(&exchange, settings, config, previous);
+                self.connectors.insert(exchange, connector);
+            }
+        }
+    }
+
+    fn spawn_connector(
+        &self,
+        exchange: &Exchange,
+        settings: Settings,
+        config: &Config,
+        previous: Option<JoinHandle<()>>,
+    ) -> Connector {
+        let pair = self.pair.to_string();
+        let stop = StopSignal::new();
+        let status = StatusReporter::new(exchange.clone(), pair.clone(), self.events_tx.clone());
+        let record = settings.record;
+        let dir = config.record.dir.clone();
+        let (recorded, recorded_pair) = (exchange.clone(), pair.clone());
+        let recorder =
+            move || record.then(|| Recorder::new(&dir, recorded.clone(), recorded_pair.clone()));
+        let supervisor = Supervisor::new(
+            exchange.clone(),
+            pair.clone(),
+            settings.queue,
+            settings.overflow,
+            self.orders_tx.clone(),
+            self.precisions.clone(),
+            status.clone(),
+            stop.clone(),
+        );
+        let endpoints = settings.endpoints.clone();
+        let connector_stop = stop.clone();
+        // a new client, recording to new files, for every restart
+        let start: Box<dyn FnMut(Ingress) -> JoinHandle<()> + Send> = match exchange {
+            Exchange::Binance => Box::new(move |tx| {
+                let mut binance_client = BinanceClient::new(pair.clone());
+                binance_client.set_endpoints(endpoints.clone());
+                if let Some(r) = recorder() {
+                    binance_client.record_to(r);
+                }
+                binance_client.do_main_loop(tx, status.clone(), connector_stop.clone())
+            }),
+            Exchange::Bitstamp => Box::new(move |tx| {
+                let mut bitstamp_client = BitstampClient::new(pair.clone());
+                bitstamp_client.set_endpoints(endpoints.clone());
+                if let Some(r) = recorder() {
+                    bitstamp_client.record_to(r);
+                }
+                bitstamp_client.do_main_loop(tx, status.clone(), connector_stop.clone())
+            }),
+        };
+        let handle = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            supervisor.run(start).await;
+        });
+        Connector {
+            settings,
+            stop,
+            handle,
+        }
+    }
+}
+
+// the book never waits for a client: a full queue skips the summary, which
+// the sequence numbers reveal, while events clients can't miss a change and
+// are dropped instead
+fn broadcast<T: Clone>(
+    clients: &mut Vec<Sender<Result<T, Status>>>,
+    msg: &T,
+    metrics: &StreamMetrics,
+    skip_when_full: bool,
+) {
+    clients.retain(|client| match client.try_send(Ok(msg.clone())) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) if skip_when_full => {
+            metrics.slow.inc();
+            true
+        }
+        Err(_) => {
+            info!("remove grpc client");
+            metrics.dropped.inc();
+            false
+        }
+    });
+    metrics.clients.set(clients.len() as i64);
+}
+
+fn spawn_book_loop(
+    pair: String,
+    depth: usize,
+    mut summary_latency: bool,
+    mut orders_rx: Receiver<Update>,
+    mut events_rx: UnboundedReceiver<ExchangeStatus>,
+    mut commands_rx: UnboundedReceiver<Command>,
+) -> PairChannels {
+    let (clients_tx, mut clients_rx) = mpsc::unbounded_channel();
+    let (event_clients_tx, mut event_clients_rx) = mpsc::unbounded_channel();
+    let (snapshots_tx, mut snapshots_rx) = mpsc::unbounded_channel::<SnapshotSender>();
+    let last_book = Arc::new(AtomicU64::new(0));
+    let precisions = Arc::new(RwLock::new(BTreeMap::new()));
+    let channels = PairChannels {
+        clients_tx: clients_tx.clone(),
+        event_clients_tx: event_clients_tx.clone(),
+        snapshots_tx: snapshots_tx.clone(),
+        last_book: last_book.clone(),
+        precisions: precisions.clone(),
+    };
+    let mut book = Book::with_depth(depth);
+    let mut statuses = BTreeMap::<String, ExchangeStatus>::new();
+    let mut clients = Vec::<SummarySender>::new();
+    let mut event_clients = Vec::<StatusSender>::new();
+    let summary_metrics = METRICS.stream(&pair, "summary");
+    let events_metrics = METRICS.stream(&pair, "events");
+    let book_update = METRICS.book_update.with_label_values(&[&pair]);
+    let spread = METRICS.spread.with_label_values(&[&pair]);
+    let mid = METRICS.mid.with_label_values(&[&pair]);
+    let orders_depth = METRICS.queue_depth.with_label_values(&[&pair, "orders"]);
+    let events_depth = METRICS.queue_depth.with_label_values(&[&pair, "events"]);
+    let labels_pair = pair.clone();
+    // 0 is left to servers that don't number their summaries
+    let mut sequence = 1;
+    let summary = move |book: &Book, statuses: &BTreeMap<String, ExchangeStatus>, sequence| {
+        let mut summary = book.to_summary();
+        summary.exchanges = statuses.values().cloned().collect();
+        summary.pair = pair.clone();
+        summary.sequence = sequence;
+        summary
+    };
+    tokio::spawn(async move {
+        // keep the client queues open until the pair is closed
+        let _open = (clients_tx, event_clients_tx, snapshots_tx);
+        loop {
+            orders_depth.set(orders_rx.len() as i64);
+            events_depth.set(events_rx.len() as i64);
+            // statuses go before the orders sent after them and the rare
+            // queues can't starve behind a backlog of orders; stopped
+            // connectors may go before the close is seen, a closed queue
+            // only disables its branch
+            tokio::select! {
+                biased;
+                command = commands_rx.recv() => match command {
+                    Some(Command::Depth(depth)) => book.set_depth(depth),
+                    Some(Command::SummaryLatency(enabled)) => summary_latency = enabled,
+                    Some(Command::Fee(exchange, fee_bps)) => book.set_fee(exchange, fee_bps),
+                    // the pipelines were dropped without closing
+                    None => return,
+                    Some(Command::Close(status)) => {
+                        let summary = summary(&book, &statuses, sequence);
+                        // the last summary and the end of the stream are
+                        // worth waiting for
+                        let ends = clients.drain(..).map(|client| {
+                            let last = Ok(summary.clone());
+                            let end = Err(status.clone());
+                            async move {
+                                let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+                                    client.send(last).await?;
+                                    client.send(end).await
+                                })
+                                .await;
+                            }
+                        });
+                        futures::future::join_all(ends).await;
+                        summary_metrics.clients.set(0);
+                        events_metrics.clients.set(0);
+                        return;
+                    }
+                },
+                Some(event) = events_rx.recv() => {
+                    statuses.insert(event.exchange.clone(), event.clone());
+                    broadcast(&mut event_clients, &event, &events_metrics, false);
+                    sequence += 1;
+                    let summary = summary(&book, &statuses, sequence);
+                    broadcast(&mut clients, &summary, &summary_metrics, true);
+                }
+                Some(uc) = clients_rx.recv() => {
+                    if uc.try_send(Ok(summary(&book, &statuses, sequence))).is_ok() {
+                        info!("new grpc client");
+                        clients.push(uc);
+                        summary_metrics.clients.set(clients.len() as i64);
+                    }
+                }
+                Some(snapshot) = snapshots_rx.recv() => {
+                    let _ = snapshot.send(summary(&book, &statuses, sequence));
+                }
+                Some(uc) = event_clients_rx.recv() => {
+                    // start with the current status of every exchange
+                    if statuses
+                        .values()
+                        .all(|status| uc.try_send(Ok(status.clone())).is_ok())
+                    {
+                        info!("new grpc events client");
+                        event_clients.push(uc);
+                        events_metrics.clients.set(event_clients.len() as i64);
+                    }
+                }
+                Some(orders) = orders_rx.recv() => {
+                    let exchange = orders.exchange().to_string();
+                    let timing = orders.timing();
+                    let dequeued = Some(SystemTime::now());
+                    if !orders.is_empty() {
+                        last_book.store(unix_ms(SystemTime::now()), Ordering::Relaxed);
+                    }
+                    if precisions.read().unwrap().get(&exchange) != Some(&orders.precisions()) {
+                        precisions.write().unwrap().insert(exchange.clone(), orders.precisions());
+                    }
+                    let start = Instant::now();
+                    book.add_orders(orders);
+                    book_update.observe(start.elapsed().as_secs_f64());
+                    let applied = Some(SystemTime::now());
+                    let stages = [
+                        ("exchange", Timing::span(timing.event, timing.received)),
+                        ("parse", Timing::span(timing.received, timing.parsed)),
+                        ("queue", Timing::span(timing.parsed, dequeued)),
+                        ("book", Timing::span(dequeued, applied)),
+                    ];
+                    sequence += 1;
+                    let mut summary = summary(&book, &statuses, sequence);
+                    if let (Some(bid), Some(ask)) = (summary.bids.first(), summary.asks.first()) {
+                        spread.set(summary.spread);
+                        mid.set((bid.price + ask.price) / 2.0);
+                    }
+                    if summary_latency {
+                        let us =
+                            |stage: Option<Duration>| stage.map_or(0, |d| d.as_micros() as u64);
+                        summary.latency = Some(Latency {
+                            exchange: exchange.clone(),
+                            exchange_us: us(stages[0].1),
+                            parse_us: us(stages[1].1),
+                            queue_us: us(stages[2].1),
+                            book_us: us(stages[3].1),
+                            sent_us: us(SystemTime::now().duration_since(UNIX_EPOCH).ok()),
+                        });
+                    }
+                    broadcast(&mut clients, &summary, &summary_metrics, true);
+                    let fanout = ("fanout", Timing::span(applied, Some(SystemTime::now())));
+                    for (stage, latency) in stages.into_iter().chain([fanout]) {
+                        if let Some(latency) = latency {
+                            METRICS.observe_stage(&exchange, &labels_pair, stage, latency);
+                        }
+                    }
+                }
+            }
+        }
+    });
+    channels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{binance_depth, MockExchange, Step};
+
+    #[test]
+    fn test_broadcast() {
+        let metrics = METRICS.stream("broadcast", "test");
+        let (fast, mut fast_rx) = mpsc::channel(2);
+        let (full, mut full_rx) = mpsc::channel(1);
+        let (closed, closed_rx) = mpsc::channel(1);
+        drop(closed_rx);
+        let mut clients = vec![fast, full.clone(), closed];
+        broadcast(&mut clients, &1, &metrics, true);
+        broadcast(&mut clients, &2, &metrics, true);
+        // the full client skipped 2 and stays, the closed one is gone
+        assert_eq!(clients.len(), 2);
+        assert_eq!(metrics.slow.get(), 1);
+        assert_eq!(fast_rx.try_recv().unwrap().unwrap(), 1);
+        assert_eq!(fast_rx.try_recv().unwrap().unwrap(), 2);
+        assert_eq!(full_rx.try_recv().unwrap().unwrap(), 1);
+        assert!(full_rx.try_recv().is_err());
+
+        // events can't be skipped, a full client is dropped
+        let mut clients = vec![full];
+        broadcast(&mut clients, &3, &metrics, false);
+        broadcast(&mut clients, &4, &metrics, false);
+        assert!(clients.is_empty());
+        assert_eq!(full_rx.try_recv().unwrap().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_close_full_client() {
+        let (_orders_tx, orders_rx) = mpsc::channel(1);
+        let (_events_tx, events_rx) = mpsc::unbounded_channel();
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let channels = spawn_book_loop(
+            "closefull".to_string(),
+            10,
+            false,
+            orders_rx,
+            events_rx,
+            commands_rx,
+        );
+        // the first summary fills the queue of the client
+        let (client, mut client_rx) = mpsc::channel(1);
+        channels.clients_tx.send(client).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        commands_tx
+            .send(Command::Close(Status::unavailable("done")))
+            .unwrap();
+        drop(channels);
+
+        // the last summary and the end wait for the client to read
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let first = client_rx.recv().await.unwrap().unwrap();
+        assert_eq!(client_rx.recv().await.unwrap().unwrap(), first);
+        let status = client_rx.recv().await.unwrap().unwrap_err();
+        assert_eq!(status.message(), "done");
+        assert!(client_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reload_log_level() {
+        let binance = MockExchange::new(
+            Exchange::Binance,
+            "ethbtc",
+            (10, 10),
+            vec![vec![Step::Repeat(
+                binance_depth(&[("0.25", "1")], &[]),
+                Duration::from_millis(50),
+            )]],
+        );
+        let endpoints = binance.endpoints();
+        let mut config = Config {
+            pairs: vec!["ethbtc".to_string()],
+            ..Config::default()
+        };
+        config.exchanges.bitstamp.enabled = false;
+        config.exchanges.binance.rest = Some(endpoints.rest);
+        config.exchanges.binance.websocket = Some(endpoints.websocket);
+        config.log.level = "info".to_string();
+        config.init_logger().unwrap();
+        assert!(!log::log_enabled!(log::Level::Debug));
+
+        let mut pipelines = Pipelines::start(config.clone()).unwrap();
+        config.log.level = "debug".to_string();
+        pipelines.reload(config);
+        assert!(log::log_enabled!(log::Level::Debug));
+        assert!(!log::log_enabled!(log::Level::Trace));
+        pipelines.close(Status::unavailable("done")).await;
+    }
+
+    #[test]
+    fn test_closed_pair() {
+        // a book loop that went away takes its queues with it
+        let (clients_tx, _) = mpsc::unbounded_channel();
+        let (event_clients_tx, _) = mpsc::unbounded_channel();
+        let (snapshots_tx, _) = mpsc::unbounded_channel();
+        let mut registry = Registry::default();
+        registry.pairs.insert(
+            "ethbtc".to_string(),
+            PairChannels {
+                clients_tx,
+                event_clients_tx,
+                snapshots_tx,
+                last_book: Default::default(),
+                precisions: Default::default(),
+            },
+        );
+        let entitlements = Entitlements::default();
+        let status = registry.summaries("ethbtc", &entitlements).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        let status = registry.snapshot("ethbtc", &entitlements).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+}
@@ -0,0 +1,377 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::book::Exchange;
+use crate::metrics::METRICS;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{info, warn};
+use prometheus::IntCounter;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// capture files are gzip compressed and only ever appended to.
+// every record is a header line followed by the raw payload:
+//
+//   <receive timestamp ns> <kind> <payload length>\n<payload>\n
+//
+// kind is F for a websocket frame and P for the "<price> <amount>"
+// precisions, which are repeated at the top of every rotated file.
+pub const FRAME: char = 'F';
+pub const PRECISIONS: char = 'P';
+
+const MAX_FILE_BYTES: u64 = 256 * 1024 * 1024;
+const MAX_FILE_AGE: Duration = Duration::from_secs(3600);
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// frames waiting for the writer thread, the ones beyond are dropped
+const QUEUE_CAPACITY: usize = 4096;
+
+// writes captures on a thread of its own, so that the connectors never wait
+// on compression or the disk; dropping it writes out the queue and closes
+// the file
+pub struct Recorder {
+    writer: Option<Writer>,
+    tx: Option<mpsc::SyncSender<Entry>>,
+    thread: Option<JoinHandle<()>>,
+    dropped: IntCounter,
+}
+
+enum Entry {
+    Precisions(u64, u64, SystemTime),
+    Frame(String, SystemTime),
+}
+
+impl Recorder {
+    pub fn new(dir: &Path, exchange: Exchange, pair: String) -> Recorder {
+        let dropped = METRICS
+            .recorder_dropped
+            .with_label_values(&[&exchange.to_string(), &pair]);
+        Recorder {
+            writer: Some(Writer {
+                dir: dir.to_path_buf(),
+                exchange,
+                pair,
+                max_bytes: MAX_FILE_BYTES,
+                max_age: MAX_FILE_AGE,
+                file: None,
+                opened_at: Instant::now(),
+                flushed_at: Instant::now(),
+                written: 0,
+                precisions: None,
+            }),
+            tx: None,
+            thread: None,
+            dropped,
+        }
+    }
+
+    pub fn with_rotation(mut self, max_bytes: u64, max_age: Duration) -> Recorder {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.max_bytes = max_bytes;
+            writer.max_age = max_age;
+        }
+        self
+    }
+
+    pub fn set_precisions(&mut self, price_prec: u64, amount_prec: u64) {
+        self.send(Entry::Precisions(
+            price_prec,
+            amount_prec,
+            SystemTime::now(),
+        ));
+    }
+
+    // frame as received by the connector at `received`
+    pub fn record(&mut self, frame: &str, received: SystemTime) {
+        self.send(Entry::Frame(frame.to_string(), received));
+    }
+
+    // the thread starts with the first entry, once the rotation is set
+    fn send(&mut self, entry: Entry) {
+        if let Some(mut writer) = self.writer.take() {
+            let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+            let name = format!("recorder-{}-{}", writer.exchange, writer.pair);
+            let thread = thread::Builder::new().name(name).spawn(move || {
+                for entry in rx {
+                    writer.write_entry(entry);
+                }
+            });
+            match thread {
+                Ok(thread) => {
+                    self.tx = Some(tx);
+                    self.thread = Some(thread);
+                }
+                Err(e) => warn!("cannot start the recorder: {}", e),
+            }
+        }
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        match entry {
+            // rare, and every later frame is read with them
+            Entry::Precisions(..) => {
+                let _ = tx.send(entry);
+            }
+            Entry::Frame(..) => {
+                if let Err(mpsc::TrySendError::Full(_)) = tx.try_send(entry) {
+                    self.dropped.inc();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Writer {
+    dir: PathBuf,
+    exchange: Exchange,
+    pair: String,
+    max_bytes: u64,
+    max_age: Duration,
+    file: Option<GzEncoder<File>>,
+    opened_at: Instant,
+    flushed_at: Instant,
+    written: u64,
+    precisions: Option<(u64, u64)>,
+}
+
+impl Writer {
+    fn write_entry(&mut self, entry: Entry) {
+        match entry {
+            Entry::Precisions(price_prec, amount_prec, at) => {
+                self.precisions = Some((price_prec, amount_prec));
+                // a new file starts with them anyway
+                if self.file.is_none() {
+                    return;
+                }
+                let payload = format!("{} {}", price_prec, amount_prec);
+                if let Err(e) = self.write(unix_ns(at), PRECISIONS, &payload) {
+                    warn!("cannot record {} precisions: {}", self.exchange, e);
+                }
+            }
+            Entry::Frame(frame, received) => {
+                if let Err(e) = self.write(unix_ns(received), FRAME, &frame) {
+                    warn!("cannot record {} frame: {}", self.exchange, e);
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, timestamp_ns: u128, kind: char, payload: &str) -> io::Result<()> {
+        if self.file.is_none()
+            || self.written >= self.max_bytes
+            || self.opened_at.elapsed() >= self.max_age
+        {
+            self.rotate()?;
+        }
+        let written = write_record(self.file.as_mut().unwrap(), timestamp_ns, kind, payload)?;
+        self.written += written;
+        if self.flushed_at.elapsed() >= FLUSH_INTERVAL {
+            self.file.as_mut().unwrap().flush()?;
+            self.flushed_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.close();
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!(
+            "{}-{}-{}.raw.gz",
+            self.exchange,
+            self.pair,
+            now_ns()
+        ));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        info!(
+            "recording {} {} to {}",
+            self.exchange,
+            self.pair,
+            path.display()
+        );
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        self.written = 0;
+        if let Some((p, a)) = self.precisions {
+            let payload = format!("{} {}", p, a);
+            self.written += write_record(&mut encoder, now_ns(), PRECISIONS, &payload)?;
+        }
+        self.file = Some(encoder);
+        self.opened_at = Instant::now();
+        self.flushed_at = Instant::now();
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some(encoder) = self.file.take() {
+            if let Err(e) = encoder.finish() {
+                warn!("cannot close {} capture: {}", self.exchange, e);
+            }
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub timestamp_ns: u128,
+    pub kind: char,
+    pub payload: String,
+}
+
+pub struct CaptureReader {
+    input: BufReader<MultiGzDecoder<File>>,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> io::Result<CaptureReader> {
+        Ok(CaptureReader {
+            input: BufReader::new(MultiGzDecoder::new(File::open(path)?)),
+        })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = String::new();
+        if self.input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid record header");
+        let mut fields = header.trim_end().split(' ');
+        let timestamp_ns = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(invalid)?;
+        let kind = fields
+            .next()
+            .and_then(|f| f.chars().next())
+            .ok_or_else(invalid)?;
+        let len: usize = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(invalid)?;
+        let mut payload = vec![0; len + 1];
+        self.input.read_exact(&mut payload)?;
+        payload.pop();
+        Ok(Some(Record {
+            timestamp_ns,
+            kind,
+            payload: String::from_utf8(payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        }))
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        self.read_record().transpose()
+    }
+}
+
+fn write_record<W: Write>(
+    w: &mut W,
+    timestamp_ns: u128,
+    kind: char,
+    payload: &str,
+) -> io::Result<u64> {
+    let header = format!("{} {} {}\n", timestamp_ns, kind, payload.len());
+    w.write_all(header.as_bytes())?;
+    w.write_all(payload.as_bytes())?;
+    w.write_all(b"\n")?;
+    Ok((header.len() + payload.len() + 1) as u64)
+}
+
+fn now_ns() -> u128 {
+    unix_ns(SystemTime::now())
+}
+
+fn unix_ns(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("infonode-recorder-{}", now_ns()));
+        let received = UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+        // every file is full after its first frame
+        let mut recorder = Recorder::new(&dir, Exchange::Binance, "ethbtc".to_string())
+            .with_rotation(40, MAX_FILE_AGE);
+        recorder.set_precisions(8, 5);
+        let frames = ["{\"bids\":[]}", "two\nlines", ""];
+        for (i, frame) in frames.iter().enumerate() {
+            recorder.record(frame, received + Duration::from_secs(i as u64));
+        }
+        drop(recorder);
+
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), frames.len());
+        for (i, path) in files.iter().enumerate() {
+            let name = path.file_name().unwrap().to_string_lossy();
+            assert!(name.starts_with("binance-ethbtc-") && name.ends_with(".raw.gz"));
+            let records: Vec<Record> = CaptureReader::open(path)
+                .unwrap()
+                .map(|record| record.unwrap())
+                .collect();
+            // every file repeats the precisions before its frames
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].kind, PRECISIONS);
+            assert_eq!(records[0].payload, "8 5");
+            assert_eq!(
+                records[1],
+                Record {
+                    timestamp_ns: 1_700_000_000_123_456_789 + i as u128 * 1_000_000_000,
+                    kind: FRAME,
+                    payload: frames[i].to_string(),
+                }
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::binance::BinanceClient;
+use crate::bitstamp::BitstampClient;
+use crate::book::Exchange;
+use crate::book::Update;
+use crate::recorder::{CaptureReader, Record, FRAME, PRECISIONS};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReplayMode {
+    RealTime,
+    Accelerated(f64),
+    AsFastAsPossible,
+}
+
+impl FromStr for ReplayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReplayMode, String> {
+        match s {
+            "realtime" => Ok(ReplayMode::RealTime),
+            "max" => Ok(ReplayMode::AsFastAsPossible),
+            _ => match s.trim_end_matches('x').parse::<f64>() {
+                Ok(speed) if speed > 0.0 => Ok(ReplayMode::Accelerated(speed)),
+                _ => Err(format!("invalid replay speed {}", s)),
+            },
+        }
+    }
+}
+
+struct Source {
+    exchange: Exchange,
+    files: VecDeque<PathBuf>,
+    reader: Option<CaptureReader>,
+    precisions: Option<(u64, u64)>,
+    next: Option<Record>,
+}
+
+impl Source {
+    // move to the next frame, applying the precisions recorded before it
+    fn advance(&mut self) {
+        self.next = None;
+        loop {
+            if self.reader.is_none() {
+                let Some(path) = self.files.pop_front() else {
+                    return;
+                };
+                info!("replaying {} from {}", self.exchange, path.display());
+                match CaptureReader::open(&path) {
+                    Ok(reader) => self.reader = Some(reader),
+                    Err(e) => {
+                        warn!("cannot open {}: {}", path.display(), e);
+                        continue;
+                    }
+                }
+            }
+            match self.reader.as_mut().unwrap().next() {
+                Some(Ok(record)) if record.kind == FRAME => {
+                    self.next = Some(record);
+                    return;
+                }
+                Some(Ok(record)) if record.kind == PRECISIONS => {
+                    let mut values = record.payload.split(' ').map(|v| v.parse::<u64>());
+                    if let (Some(Ok(p)), Some(Ok(a))) = (values.next(), values.next()) {
+                        self.precisions = Some((p, a));
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    // a capture cut by a crash ends with a truncated record
+                    warn!("stop reading {} capture: {}", self.exchange, e);
+                    self.reader = None;
+                }
+                None => self.reader = None,
+            }
+        }
+    }
+
+    fn parse(&self, frame: &str) -> Option<Update> {
+        let (p_prec, a_prec) = self.precisions?;
+        match self.exchange {
+            Exchange::Binance => BinanceClient::parse(frame, p_prec, a_prec),
+            Exchange::Bitstamp => BitstampClient::parse(frame, p_prec, a_prec),
+        }
+    }
+}
+
+pub struct ReplayClient {
+    pair: String,
+    mode: ReplayMode,
+    sources: Vec<Source>,
+}
+
+impl ReplayClient {
+    pub fn new(pair: String, mode: ReplayMode) -> ReplayClient {
+        ReplayClient {
+            pair,
+            mode,
+            sources: Vec::new(),
+        }
+    }
+
+    // capture files written by the recorder for exchange and pair, oldest first
+    pub fn captures(dir: &Path, exchange: &Exchange, pair: &str) -> io::Result<Vec<PathBuf>> {
+        let prefix = format!("{}-{}-", exchange, pair);
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(".raw.gz") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    pub fn add_capture(&mut self, exchange: Exchange, files: Vec<PathBuf>) {
+        self.sources.push(Source {
+            exchange,
+            files: files.into(),
+            reader: None,
+            precisions: None,
+            next: None,
+        });
+    }
+
+    pub fn do_main_loop(mut self, tx: Sender<Update>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            info!("replay of {} started mode={:?}", self.pair, self.mode);
+            for source in self.sources.iter_mut() {
+                source.advance();
+            }
+            let started = Instant::now();
+            let mut first_ns = None;
+            let mut frames = 0;
+            // merge all the exchanges by receive timestamp
+            while let Some(source) = self
+                .sources
+                .iter_mut()
+                .filter(|s| s.next.is_some())
+                .min_by_key(|s| s.next.as_ref().unwrap().timestamp_ns)
+            {
+                let record = source.next.take().unwrap();
+                let first_ns = *first_ns.get_or_insert(record.timestamp_ns);
+                // clock steps and merged files may go back in time
+                let elapsed =
+                    Duration::from_nanos(record.timestamp_ns.saturating_sub(first_ns) as u64);
+                let due = match self.mode {
+                    ReplayMode::RealTime => Some(elapsed),
+                    ReplayMode::Accelerated(speed) => Some(elapsed.div_f64(speed)),
+                    ReplayMode::AsFastAsPossible => None,
+                };
+                if let Some(wait) = due.and_then(|d| d.checked_sub(started.elapsed())) {
+                    thread::sleep(wait);
+                }
+                if let Some(orders) = source.parse(&record.payload) {
+                    // waits for room in the queue, replays are never lossy
+                    if tx.blocking_send(orders).is_err() {
+                        return;
+                    }
+                }
+                frames += 1;
+                source.advance();
+            }
+            info!("replay of {} finished after {} frames", self.pair, frames);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::Book;
+    use crate::recorder::Recorder;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_replay_captures() {
+        let dir = std::env::temp_dir().join(format!("infonode-replay-{}", std::process::id()));
+        let mut binance = Recorder::new(&dir, Exchange::Binance, "ethbtc".to_string());
+        binance.set_precisions(10, 10);
+        binance.record(
+            r#"{"lastUpdateId":1,"bids":[["0.25","2"]],"asks":[["0.75","1"]]}"#,
+            SystemTime::now(),
+        );
+        binance.record("not json", SystemTime::now());
+        drop(binance);
+        let mut bitstamp = Recorder::new(&dir, Exchange::Bitstamp, "ethbtc".to_string());
+        bitstamp.set_precisions(10, 10);
+        bitstamp.record(
+            r#"{"event":"bts:subscription_succeeded","channel":"order_book_ethbtc","data":{}}"#,
+            SystemTime::now(),
+        );
+        bitstamp.record(
+            r#"{"event":"data","data":{"bids":[["0.5","3"]],"asks":[["1.5","4"]]}}"#,
+            SystemTime::now(),
+        );
+        drop(bitstamp);
+
+        let mut replay = ReplayClient::new("ethbtc".to_string(), ReplayMode::AsFastAsPossible);
+        for exchange in [Exchange::Binance, Exchange::Bitstamp] {
+            let files = ReplayClient::captures(&dir, &exchange, "ethbtc").unwrap();
+            assert_eq!(files.len(), 1);
+            replay.add_capture(exchange, files);
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        replay.do_main_loop(tx).join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut book = Book::new();
+        while let Ok(orders) = rx.try_recv() {
+            book.add_orders(orders);
+        }
+        let summary = book.to_summary();
+        assert_eq!(summary.bids.len(), 2);
+        assert_eq!(summary.bids[0].exchange, "bitstamp");
+        assert_eq!(summary.bids[0].price, 0.5);
+        assert_eq!(summary.asks[0].exchange, "binance");
+        assert_eq!(summary.asks[0].price, 0.75);
+        assert_eq!(summary.asks[1].exchange, "bitstamp");
+        assert_eq!(summary.spread, 0.25);
+    }
+
+    #[test]
+    fn test_replay_pacing() {
+        let dir = std::env::temp_dir().join(format!("infonode-pacing-{}", std::process::id()));
+        let mut binance = Recorder::new(&dir, Exchange::Binance, "ethbtc".to_string());
+        binance.set_precisions(10, 10);
+        let start = SystemTime::now();
+        // the third frame comes from before a clock step back
+        for ms in [100, 300, 0, 500] {
+            binance.record(
+                r#"{"lastUpdateId":1,"bids":[["0.25","2"]],"asks":[]}"#,
+                start + Duration::from_millis(ms),
+            );
+        }
+        drop(binance);
+
+        let mut replay = ReplayClient::new("ethbtc".to_string(), ReplayMode::Accelerated(2.0));
+        let files = ReplayClient::captures(&dir, &Exchange::Binance, "ethbtc").unwrap();
+        replay.add_capture(Exchange::Binance, files);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let started = Instant::now();
+        replay.do_main_loop(tx).join().unwrap();
+        let elapsed = started.elapsed();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut updates = 0;
+        while rx.try_recv().is_ok() {
+            updates += 1;
+        }
+        assert_eq!(updates, 4);
+        // 400ms of capture at twice the speed
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_replay_mode() {
+        assert_eq!("realtime".parse(), Ok(ReplayMode::RealTime));
+        assert_eq!("max".parse(), Ok(ReplayMode::AsFastAsPossible));
+        assert_eq!("10x".parse(), Ok(ReplayMode::Accelerated(10.0)));
+        assert!("0".parse::<ReplayMode>().is_err());
+    }
+}
@@ -0,0 +1,251 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::auth::{bearer, Authenticator, Entitlements};
+use crate::book::Exchange;
+use crate::gateway::{status_json, summary_json};
+use crate::orderbook::Summary;
+use crate::pipeline::Registry;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use json::JsonValue;
+use log::info;
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tonic::{Code, Status};
+
+// how long to wait for a book loop to answer
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+struct Context {
+    registry: Arc<RwLock<Registry>>,
+    authenticator: Authenticator,
+    stale_after: Duration,
+}
+
+// json snapshots for scripts, a trailing slash is ignored:
+//   GET /book/<pair>?depth=N&exchanges=binance,bitstamp
+//   GET /instruments
+//   GET /status
+// in-flight requests finish after the shutdown, new ones are refused
+pub async fn serve(
+    listener: TcpListener,
+    registry: Arc<RwLock<Registry>>,
+    authenticator: Authenticator,
+    stale_after: Duration,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<(), String> {
+    let context = Context {
+        registry,
+        authenticator,
+        stale_after,
+    };
+    let incoming = AddrIncoming::from_listener(listener).map_err(|e| e.to_string())?;
+    info!("rest api listening on http://{}", incoming.local_addr());
+    Server::builder(incoming)
+        .serve(make_service_fn(move |_| {
+            let context = context.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| handle(context.clone(), request)))
+            }
+        }))
+        .with_graceful_shutdown(async move {
+            let _ = shutdown.changed().await;
+        })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn handle(context: Context, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let body = match route(&context, &request).await {
+        Ok(body) => body,
+        Err(status) => return Ok(error(&status)),
+    };
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.dump()))
+        .unwrap())
+}
+
+async fn route(context: &Context, request: &Request<Body>) -> Result<JsonValue, Status> {
+    if request.method() != Method::GET {
+        return Err(Status::unimplemented("only GET is supported"));
+    }
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let token = bearer(header("authorization"), header("x-api-key"));
+    let entitlements = context.authenticator.entitlements(token)?;
+    let path = request.uri().path();
+    match path.trim_end_matches('/') {
+        "/instruments" => Ok(instruments(context, &entitlements)),
+        "/status" => status(context, &entitlements).await,
+        trimmed => match trimmed.strip_prefix("/book/") {
+            Some(pair) => book(context, entitlements, pair, request.uri().query()).await,
+            None => Err(Status::not_found(format!("no route {}", path))),
+        },
+    }
+}
+
+async fn book(
+    context: &Context,
+    mut entitlements: Entitlements,
+    pair: &str,
+    query: Option<&str>,
+) -> Result<JsonValue, Status> {
+    let query = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes());
+    for (key, value) in query {
+        match key.as_ref() {
+            "depth" => match value.parse() {
+                Ok(depth) if depth > 0 => entitlements.limit_depth(depth),
+                _ => return Err(Status::invalid_argument("depth must be positive")),
+            },
+            "exchanges" => {
+                let mut exchanges = Vec::new();
+                for exchange in value.split(',').filter(|e| !e.is_empty()) {
+                    let exchange = Exchange::from_str(exchange)
+                        .map_err(|_| {
+                            Status::invalid_argument(format!("unknown exchange {}", exchange))
+                        })?
+                        .to_string();
+                    if !entitlements.allows_exchange(&exchange) {
+                        return Err(Status::permission_denied(format!(
+                            "exchange {} not allowed",
+                            exchange
+                        )));
+                    }
+                    exchanges.push(exchange);
+                }
+                if !exchanges.is_empty() {
+                    entitlements.exchanges = exchanges;
+                }
+            }
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown parameter {}",
+                    key
+                )))
+            }
+        }
+    }
+    Ok(summary_json(&snapshot(context, &entitlements, pair).await?))
+}
+
+fn instruments(context: &Context, entitlements: &Entitlements) -> JsonValue {
+    let instruments = context.registry.read().unwrap().instruments(entitlements);
+    let instruments = instruments
+        .into_iter()
+        .map(|instrument| {
+            let exchanges = instrument
+                .precisions
+                .into_iter()
+                .map(|precision| {
+                    json::object! {
+                        exchange: precision.exchange,
+                        price_precision: precision.price,
+                        amount_precision: precision.amount,
+                    }
+                })
+                .collect::<Vec<_>>();
+            json::object! {
+                pair: instrument.pair,
+                default: instrument.default,
+                exchanges: exchanges,
+            }
+        })
+        .collect::<Vec<_>>();
+    json::object! { instruments: instruments }
+}
+
+async fn status(context: &Context, entitlements: &Entitlements) -> Result<JsonValue, Status> {
+    let (healthy, pairs) = {
+        let registry = context.registry.read().unwrap();
+        let pairs = registry
+            .pairs
+            .keys()
+            .filter(|pair| entitlements.allows_pair(pair))
+            .cloned()
+            .collect::<Vec<_>>();
+        (registry.fresh(context.stale_after), pairs)
+    };
+    let mut statuses = Vec::new();
+    for pair in pairs {
+        let summary = snapshot(context, entitlements, &pair).await?;
+        let last_book = context
+            .registry
+            .read()
+            .unwrap()
+            .pairs
+            .get(&pair)
+            .map(|channels| channels.last_book.load(Ordering::Relaxed));
+        statuses.push(json::object! {
+            pair: pair,
+            last_book: last_book.filter(|ms| *ms > 0),
+            exchanges: summary.exchanges.iter().map(status_json).collect::<Vec<_>>(),
+        });
+    }
+    Ok(json::object! { healthy: healthy, pairs: statuses })
+}
+
+// the current summary, as a new grpc client would get it first
+async fn snapshot(
+    context: &Context,
+    entitlements: &Entitlements,
+    pair: &str,
+) -> Result<Summary, Status> {
+    let (_, rx) = context
+        .registry
+        .read()
+        .unwrap()
+        .snapshot(pair, entitlements)?;
+    match tokio::time::timeout(SNAPSHOT_TIMEOUT, rx).await {
+        Ok(Ok(summary)) => Ok(entitlements.filter_summary(summary)),
+        _ => Err(Status::unavailable(format!("no book for {}", pair))),
+    }
+}
+
+fn error(status: &Status) -> Response<Body> {
+    let code = match status.code() {
+        Code::InvalidArgument => StatusCode::BAD_REQUEST,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::Unimplemented => StatusCode::METHOD_NOT_ALLOWED,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let body = json::object! { error: status.message() };
+    Response::builder()
+        .status(code)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.dump()))
+        .unwrap()
+}
@@ -0,0 +1,422 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+use crate::orderbook::{Empty, ExchangeStatus, Instrument, Summary, SummaryRequest};
+use crate::status::Backoff;
+use bigdecimal::BigDecimal;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Code, Request, Status, Streaming};
+
+// where infonode-server listens and how to authenticate
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    // https when a ca is given
+    pub server: String,
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // name in the server certificate, the server host by default
+    pub domain: Option<String>,
+    // api key sent as a bearer token
+    pub token: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig::new("http://[::1]:1079")
+    }
+}
+
+impl ClientConfig {
+    pub fn new(server: &str) -> ClientConfig {
+        ClientConfig {
+            server: server.to_string(),
+            ca: None,
+            cert: None,
+            key: None,
+            domain: None,
+            token: None,
+        }
+    }
+
+    pub async fn connect(&self) -> Result<Client, String> {
+        let server = match self.ca {
+            Some(_) => self.server.replacen("http://", "https://", 1),
+            None => self.server.clone(),
+        };
+        let mut endpoint = Channel::from_shared(server).map_err(|e| e.to_string())?;
+        if let Some(ca) = &self.ca {
+            let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read(ca)?));
+            if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+                tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+            }
+            if let Some(domain) = &self.domain {
+                tls = tls.domain_name(domain);
+            }
+            endpoint = endpoint.tls_config(tls).map_err(|e| e.to_string())?;
+        }
+        let channel = endpoint
+            .connect()
+            .await
+            .map_err(|e| format!("cannot connect to {}: {}", self.server, e))?;
+        Ok(Client {
+            inner: OrderbookAggregatorClient::new(channel),
+            token: self.token.clone(),
+        })
+    }
+}
+
+fn read(path: &PathBuf) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+}
+
+// the grpc calls, with the api key attached
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: OrderbookAggregatorClient<Channel>,
+    token: Option<String>,
+}
+
+impl Client {
+    #[allow(clippy::result_large_err)]
+    fn request<T>(&self, message: T) -> Result<Request<T>, Status> {
+        let mut request = Request::new(message);
+        if let Some(token) = &self.token {
+            let value = format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| Status::invalid_argument("invalid api key"))?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+
+    pub async fn instruments(&mut self) -> Result<Vec<Instrument>, Status> {
+        let request = self.request(Empty {})?;
+        Ok(self
+            .inner
+            .instruments(request)
+            .await?
+            .into_inner()
+            .instruments)
+    }
+
+    // an empty pair streams the first pair configured on the server
+    pub async fn summaries(&mut self, pair: &str) -> Result<Streaming<Summary>, Status> {
+        let request = self.request(SummaryRequest {
+            pair: pair.to_string(),
+        })?;
+        Ok(self.inner.book_summary(request).await?.into_inner())
+    }
+
+    pub async fn events(&mut self) -> Result<Streaming<ExchangeStatus>, Status> {
+        let request = self.request(Empty {})?;
+        Ok(self.inner.exchange_events(request).await?.into_inner())
+    }
+}
+
+// a level with the precisions of its exchange applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    pub exchange: String,
+    pub price: BigDecimal,
+    pub amount: BigDecimal,
+}
+
+// the top of the aggregated book, rebuilt from every summary
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalBook {
+    pub pair: String,
+    // best first
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    pub exchanges: BTreeMap<String, ExchangeStatus>,
+    // of the last summary, 0 when the server doesn't number them
+    pub sequence: u64,
+}
+
+impl LocalBook {
+    // summaries carry the whole top of the book, nothing is kept from before
+    pub fn apply(&mut self, summary: &Summary, precisions: &BTreeMap<String, (u64, u64)>) {
+        let levels = |levels: &[crate::orderbook::Level]| {
+            levels
+                .iter()
+                .map(|level| {
+                    let (price, amount) = match precisions.get(&level.exchange) {
+                        Some((price, amount)) => (Some(*price), Some(*amount)),
+                        None => (None, None),
+                    };
+                    Level {
+                        exchange: level.exchange.clone(),
+                        price: decimal(level.price, price),
+                        amount: decimal(level.amount, amount),
+                    }
+                })
+                .collect()
+        };
+        self.pair = summary.pair.clone();
+        self.bids = levels(&summary.bids);
+        self.asks = levels(&summary.asks);
+        self.exchanges = summary
+            .exchanges
+            .iter()
+            .map(|status| (status.exchange.clone(), status.clone()))
+            .collect();
+        self.sequence = summary.sequence;
+    }
+
+    pub fn best_bid(&self) -> Option<&Level> {
+        self.bids.first()
+    }
+
+    pub fn best_ask(&self) -> Option<&Level> {
+        self.asks.first()
+    }
+
+    pub fn spread(&self) -> Option<BigDecimal> {
+        Some(&self.best_ask()?.price - &self.best_bid()?.price)
+    }
+
+    pub fn mid(&self) -> Option<BigDecimal> {
+        Some((&self.best_ask()?.price + &self.best_bid()?.price) / BigDecimal::from(2))
+    }
+}
+
+// prices travel as doubles, round them back to what the exchange sent
+fn decimal(value: f64, precision: Option<u64>) -> BigDecimal {
+    let decimal = BigDecimal::from_str(&value.to_string()).unwrap_or_default();
+    match precision {
+        Some(precision) => decimal.with_prec(precision),
+        None => decimal,
+    }
+}
+
+// calls a retry can't fix
+fn permanent(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unauthenticated
+            | Code::PermissionDenied
+            | Code::NotFound
+            | Code::InvalidArgument
+            | Code::Unimplemented
+    )
+}
+
+// updates lost before a summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Missed {
+    Updates(u64),
+    // after a reconnect to a server that restarted or doesn't number summaries
+    Unknown,
+}
+
+// a restarted server is only noticed while it counts below the last sequence
+fn missed(last: u64, sequence: u64, reconnected: bool) -> Option<Missed> {
+    if last == 0 || sequence == 0 || sequence < last {
+        return reconnected.then_some(Missed::Unknown);
+    }
+    match sequence - last {
+        0 | 1 => None,
+        gap => Some(Missed::Updates(gap - 1)),
+    }
+}
+
+// the local book of a pair, reconnecting with backoff when the stream breaks
+pub struct Subscription {
+    config: ClientConfig,
+    pair: String,
+    client: Option<Client>,
+    stream: Option<Streaming<Summary>>,
+    precisions: BTreeMap<String, (u64, u64)>,
+    // exchanges looked up since connecting
+    looked_up: BTreeSet<String>,
+    backoff: Backoff,
+    book: LocalBook,
+    // the stream broke since the last summary
+    reconnected: bool,
+    missed: Option<Missed>,
+}
+
+impl Subscription {
+    // an empty pair follows the first pair configured on the server
+    pub fn new(config: ClientConfig, pair: &str) -> Subscription {
+        Subscription {
+            config,
+            pair: pair.to_string(),
+            client: None,
+            stream: None,
+            precisions: BTreeMap::new(),
+            looked_up: BTreeSet::new(),
+            backoff: Backoff::new(),
+            book: LocalBook::default(),
+            reconnected: false,
+            missed: None,
+        }
+    }
+
+    pub fn book(&self) -> &LocalBook {
+        &self.book
+    }
+
+    // updates lost right before the last summary
+    pub fn missed(&self) -> Option<Missed> {
+        self.missed
+    }
+
+    // the book after the next summary, errors are only returned when retrying can't help
+    pub async fn next(&mut self) -> Result<&LocalBook, Status> {
+        loop {
+            match self.receive().await {
+                Ok(summary) => {
+                    self.backoff.reset();
+                    // nothing to miss before the first summary
+                    self.missed = if self.book.pair.is_empty() {
+                        None
+                    } else {
+                        missed(self.book.sequence, summary.sequence, self.reconnected)
+                    };
+                    self.reconnected = false;
+                    self.book.apply(&summary, &self.precisions);
+                    return Ok(&self.book);
+                }
+                Err(status) if permanent(&status) => return Err(status),
+                Err(status) => {
+                    let delay = self.backoff.delay();
+                    warn!("{}, reconnecting in {}s", status.message(), delay.as_secs());
+                    self.client = None;
+                    self.stream = None;
+                    self.reconnected = true;
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    async fn receive(&mut self) -> Result<Summary, Status> {
+        if self.stream.is_none() {
+            let mut client = self.config.connect().await.map_err(Status::unavailable)?;
+            self.looked_up.clear();
+            self.precisions = self.lookup(&mut client).await?;
+            self.stream = Some(client.summaries(&self.pair).await?);
+            self.client = Some(client);
+        }
+        let summary = self
+            .stream
+            .as_mut()
+            .unwrap()
+            .message()
+            .await?
+            .ok_or_else(|| Status::unavailable("summaries ended"))?;
+        // exchanges that sent their first book after we connected
+        let unknown = summary
+            .bids
+            .iter()
+            .chain(summary.asks.iter())
+            .any(|level| !self.looked_up.contains(&level.exchange));
+        if unknown {
+            let mut client = self.client.clone().unwrap();
+            self.precisions = self.lookup(&mut client).await?;
+            for level in summary.bids.iter().chain(summary.asks.iter()) {
+                self.looked_up.insert(level.exchange.clone());
+            }
+        }
+        Ok(summary)
+    }
+
+    async fn lookup(
+        &mut self,
+        client: &mut Client,
+    ) -> Result<BTreeMap<String, (u64, u64)>, Status> {
+        let instruments = client.instruments().await?;
+        let instrument = instruments
+            .into_iter()
+            .find(|instrument| match self.pair.as_str() {
+                "" => instrument.default,
+                pair => instrument.pair == pair,
+            });
+        let precisions = instrument
+            .map(|instrument| instrument.precisions)
+            .unwrap_or_default();
+        for precision in &precisions {
+            self.looked_up.insert(precision.exchange.clone());
+        }
+        Ok(precisions
+            .into_iter()
+            .map(|precision| (precision.exchange, (precision.price, precision.amount)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook;
+
+    #[test]
+    fn test_local_book() {
+        let level = |exchange: &str, price, amount| orderbook::Level {
+            exchange: exchange.to_string(),
+            price,
+            amount,
+        };
+        let summary = Summary {
+            pair: "ethbtc".to_string(),
+            spread: 0.1,
+            bids: vec![level("binance", 0.1 + 0.2, 1.0 / 3.0)],
+            asks: vec![level("bitstamp", 0.4, 2.0)],
+            ..Summary::default()
+        };
+        let precisions = BTreeMap::from([("binance".to_string(), (4, 3))]);
+        let mut book = LocalBook::default();
+        book.apply(&summary, &precisions);
+
+        let dec = |s| BigDecimal::from_str(s).unwrap();
+        assert_eq!(book.pair, "ethbtc");
+        assert_eq!(book.best_bid().unwrap().price, dec("0.3"));
+        assert_eq!(book.best_bid().unwrap().amount, dec("0.333"));
+        // no precision known for bitstamp
+        assert_eq!(book.best_ask().unwrap().price, dec("0.4"));
+        assert_eq!(book.spread(), Some(dec("0.1")));
+        assert_eq!(book.mid(), Some(dec("0.35")));
+
+        book.apply(&Summary::default(), &precisions);
+        assert_eq!(book.best_bid(), None);
+        assert_eq!(book.spread(), None);
+    }
+
+    #[test]
+    fn test_missed() {
+        assert_eq!(missed(4, 5, false), None);
+        // a new stream starts with the current book
+        assert_eq!(missed(4, 4, true), None);
+        assert_eq!(missed(4, 7, true), Some(Missed::Updates(2)));
+        assert_eq!(missed(4, 2, true), Some(Missed::Unknown));
+        assert_eq!(missed(0, 0, true), Some(Missed::Unknown));
+        assert_eq!(missed(0, 0, false), None);
+    }
+}
@@ -20,114 +20,161 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
  * IN THE SOFTWARE.
  */
-use crossbeam_channel::Sender;
-use crossbeam_channel::{select, unbounded};
-use futures::executor::block_on;
-use log::info;
-use orderbook::orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer};
-use orderbook::{Empty, Summary};
-use std::env;
-use std::thread;
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{transport::Server, Request, Response, Status};
-
-pub mod book;
-use crate::book::Book;
-
-pub mod binance;
-use crate::binance::BinanceClient;
-
-pub mod bitstamp;
-use crate::bitstamp::BitstampClient;
-
-pub mod orderbook {
-    tonic::include_proto!("orderbook");
-}
-
-#[derive(Debug)]
-struct MyOrderbookAggregator {
-    clients_tx: Sender<tokio::sync::mpsc::Sender<Result<Summary, Status>>>,
-}
-
-#[tonic::async_trait]
-impl OrderbookAggregator for MyOrderbookAggregator {
-    type BookSummaryStream = ReceiverStream<Result<Summary, Status>>;
-
-    async fn book_summary(
-        &self,
-        _request: Request<Empty>,
-    ) -> Result<Response<Self::BookSummaryStream>, Status> {
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
-        self.clients_tx.send(tx).unwrap();
-        Ok(Response::new(ReceiverStream::new(rx)))
-    }
-}
+use clap::Parser;
+use infonode::auth::Authenticator;
+use infonode::config::{Cli, Config, GrpcWebConfig};
+use infonode::orderbook::orderbook_aggregator_server::OrderbookAggregatorServer;
+use infonode::pipeline::Pipelines;
+use infonode::service::{health, reflection, MyOrderbookAggregator};
+use infonode::tls::ServerTls;
+use infonode::{gateway, metrics, rest};
+use log::{error, info, warn};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+use tonic::transport::Server;
+use tonic::Status;
+use tonic_web::GrpcWebLayer;
+use tower::util::option_layer;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    simple_logger::init_with_level(log::Level::Debug).unwrap();
-
-    // parse command line
-    let pair = env::args()
-        .nth(1)
-        .unwrap_or_else(|| panic!("run ./infonode-server <pair>"));
+    // parse command line and configuration file
+    let cli = Cli::parse();
+    let config = Config::load(cli.clone()).unwrap_or_else(|e| {
+        eprintln!("infonode-server: {}", e);
+        process::exit(2);
+    });
+    config.init_logger()?;
 
-    // create queues
-    let (orders_tx, orders_rx) = unbounded();
-    let (clients_tx, clients_rx) = unbounded();
+    let pipelines = Pipelines::start(config.clone())?;
 
     // create grpc service
-    let aggregator = MyOrderbookAggregator {
-        clients_tx: clients_tx.clone(),
-    };
-
-    // main event loop
-    let mut book = Book::new();
-    let mut clients = Vec::<tokio::sync::mpsc::Sender<Result<Summary, Status>>>::new();
-    thread::spawn(move || loop {
-        select! {
-            recv(orders_rx) -> orders => {
-                book.add_orders(orders.unwrap());
-                let summary = book.to_summary();
-                clients.retain_mut(|client| {
-                     let s =  block_on(client.send(Ok(summary.clone()))).is_ok();
-                     if !s {
-                        info!("remove grpc client");
-                     }
-                     return s;
-                }
-                );
-
-            }
-            recv(clients_rx) -> client => {
-                let summary = book.to_summary();
-                let uc = client.unwrap();
-                if block_on(uc.send(Ok(summary))).is_ok() {
-                    info!("new grpc client");
-                    clients.push(uc);
+    let aggregator = MyOrderbookAggregator::new(pipelines.registry());
+    let health = health(
+        pipelines.registry(),
+        Duration::from_secs(config.stale_after),
+    )
+    .await;
+    let pipelines = Arc::new(Mutex::new(pipelines));
+    let server_tls = config.tls.as_ref().map(ServerTls::new).transpose()?;
+
+    // reapply the configuration and reload the certificates on SIGHUP
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reloaded = pipelines.clone();
+    let reloaded_tls = server_tls.clone();
+    let authenticator = Authenticator::new(&config.auth);
+    let reloaded_auth = authenticator.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match Config::load(cli.clone()) {
+                Ok(config) => {
+                    info!("reload configuration");
+                    match (&reloaded_tls, &config.tls) {
+                        (Some(server_tls), Some(tls)) => {
+                            if let Err(e) = server_tls.reload(tls) {
+                                error!("keep current certificates: {}", e);
+                            }
+                        }
+                        (None, None) => {}
+                        _ => warn!("enabling or disabling tls needs a restart"),
+                    }
+                    reloaded_auth.reload(&config.auth);
+                    reloaded.lock().await.reload(config);
                 }
+                Err(e) => error!("keep current configuration: {}", e),
             }
         }
     });
 
-    // binance client setup and wiring
-    let binance_client = BinanceClient::new(pair.to_string());
-    binance_client.do_main_loop(orders_tx.clone());
+    // the side servers stop together with the grpc server
+    let (stop, stopped) = watch::channel(());
 
-    // bitstamp client setup and wiring
-    let bitstamp_client = BitstampClient::new(pair.to_string());
-    bitstamp_client.do_main_loop(orders_tx.clone());
+    if let Some(metrics) = &config.metrics {
+        let listen = metrics.listen;
+        let stopped = stopped.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listen, stopped).await {
+                error!("{}", e);
+            }
+        });
+    }
 
-    // setup address for grpc server binding
-    let addr = "[::1]:1079".parse()?;
+    if let Some(gateway) = &config.gateway {
+        let listener = TcpListener::bind(gateway.listen).await?;
+        tokio::spawn(gateway::serve(
+            listener,
+            pipelines.lock().await.registry(),
+            authenticator.clone(),
+            stopped.clone(),
+        ));
+    }
 
-    info!("grpc server listening for client on port=1079");
+    if let Some(rest) = &config.rest {
+        let listener = TcpListener::bind(rest.listen).await?;
+        let registry = pipelines.lock().await.registry();
+        let authenticator = authenticator.clone();
+        let stale_after = Duration::from_secs(config.stale_after);
+        let stopped = stopped.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                rest::serve(listener, registry, authenticator, stale_after, stopped).await
+            {
+                error!("{}", e);
+            }
+        });
+    }
+
+    info!("grpc server listening for client on {}", config.listen);
+
+    // close the streams on SIGTERM/SIGINT, then let the server drain
+    let mut terminate = signal(SignalKind::terminate())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        info!("shutting down");
+        pipelines
+            .lock()
+            .await
+            .close(Status::unavailable("server shutting down"))
+            .await;
+        let _ = stop.send(());
+    };
+
+    // browsers speak grpc-web over http/1.1, behind cors
+    let cors = config
+        .grpc_web
+        .as_ref()
+        .map(GrpcWebConfig::cors)
+        .transpose()?;
+    let grpc_web = cors.is_some();
 
     // run grpc server
-    Server::builder()
-        .add_service(OrderbookAggregatorServer::new(aggregator))
-        .serve(addr)
-        .await?;
+    let router = Server::builder()
+        .accept_http1(grpc_web)
+        .layer(option_layer(cors))
+        .layer(option_layer(grpc_web.then(GrpcWebLayer::new)))
+        .add_service(health)
+        .add_service(reflection()?)
+        .add_service(OrderbookAggregatorServer::with_interceptor(
+            aggregator,
+            authenticator,
+        ));
+    match server_tls {
+        Some(server_tls) => {
+            let listener = TcpListener::bind(config.listen).await?;
+            router
+                .serve_with_incoming_shutdown(server_tls.incoming(listener), shutdown)
+                .await?
+        }
+        None => router.serve_with_shutdown(config.listen, shutdown).await?,
+    }
 
+    info!("server stopped");
     Ok(())
 }
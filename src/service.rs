@@ -0,0 +1,214 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::auth::{restrict, Entitlements};
+use crate::orderbook;
+use crate::orderbook::orderbook_aggregator_server::{
+    OrderbookAggregator, OrderbookAggregatorServer,
+};
+use crate::orderbook::{Empty, ExchangeStatus, InstrumentList, Summary, SummaryRequest};
+use crate::pipeline::Registry;
+use log::info;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+use tonic_health::pb::health_server::{Health, HealthServer};
+use tonic_health::server::health_reporter;
+use tonic_health::ServingStatus;
+use tonic_reflection::pb::server_reflection_server::{ServerReflection, ServerReflectionServer};
+
+const HEALTH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub struct MyOrderbookAggregator {
+    registry: Arc<RwLock<Registry>>,
+}
+
+impl MyOrderbookAggregator {
+    pub fn new(registry: Arc<RwLock<Registry>>) -> MyOrderbookAggregator {
+        MyOrderbookAggregator { registry }
+    }
+}
+
+#[tonic::async_trait]
+impl OrderbookAggregator for MyOrderbookAggregator {
+    type BookSummaryStream = StatusLast<ReceiverStream<Result<Summary, Status>>>;
+    type ExchangeEventsStream = StatusLast<ReceiverStream<Result<ExchangeStatus, Status>>>;
+
+    async fn book_summary(
+        &self,
+        request: Request<SummaryRequest>,
+    ) -> Result<Response<Self::BookSummaryStream>, Status> {
+        let entitlements = entitlements(&request);
+        let pair = request.into_inner().pair;
+        let (_, rx) = self
+            .registry
+            .read()
+            .unwrap()
+            .summaries(&pair, &entitlements)?;
+        Ok(Response::new(StatusLast::new(ReceiverStream::new(rx))))
+    }
+
+    async fn exchange_events(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<Self::ExchangeEventsStream>, Status> {
+        let entitlements = entitlements(&request);
+        let mut registry = self.registry.write().unwrap();
+        if registry.closing {
+            return Err(Status::unavailable("server shutting down"));
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        for (pair, channels) in &registry.pairs {
+            channels
+                .event_clients_tx
+                .send(tx.clone())
+                .map_err(|_| Status::unavailable(format!("pair {} closed", pair)))?;
+        }
+        // pairs added by a reload pick it up from here
+        registry.event_clients.retain(|tx| !tx.is_closed());
+        registry.event_clients.push(tx);
+        if entitlements != Entitlements::default() {
+            rx = restrict(rx, None, move |status| {
+                entitlements.allows_status(&status).then_some(status)
+            });
+        }
+        Ok(Response::new(StatusLast::new(ReceiverStream::new(rx))))
+    }
+
+    async fn instruments(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<InstrumentList>, Status> {
+        let entitlements = entitlements(&request);
+        let instruments = self.registry.read().unwrap().instruments(&entitlements);
+        Ok(Response::new(InstrumentList { instruments }))
+    }
+}
+
+// tonic drops the messages it has buffered when the stream yields an error,
+// so the ones before a closing status get a poll to go out first
+pub struct StatusLast<S> {
+    inner: S,
+    sent: bool,
+    status: Option<Status>,
+}
+
+impl<S> StatusLast<S> {
+    pub fn new(inner: S) -> StatusLast<S> {
+        StatusLast {
+            inner,
+            sent: false,
+            status: None,
+        }
+    }
+}
+
+impl<T, S: Stream<Item = Result<T, Status>> + Unpin> Stream for StatusLast<S> {
+    type Item = Result<T, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(status) = self.status.take() {
+            return Poll::Ready(Some(Err(status)));
+        }
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Err(status))) if self.sent => {
+                self.sent = false;
+                self.status = Some(status);
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Ready(item) => {
+                self.sent = matches!(item, Some(Ok(_)));
+                Poll::Ready(item)
+            }
+            Poll::Pending => {
+                self.sent = false;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// set by the authenticator, everything when the call bypassed it
+fn entitlements<T>(request: &Request<T>) -> Entitlements {
+    request
+        .extensions()
+        .get::<Entitlements>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+// NOT_SERVING until every pair has a book, and again once they go stale
+pub async fn health(
+    registry: Arc<RwLock<Registry>>,
+    stale_after: Duration,
+) -> HealthServer<impl Health> {
+    let (mut reporter, service) = health_reporter();
+    reporter
+        .set_service_status("", ServingStatus::NotServing)
+        .await;
+    reporter
+        .set_not_serving::<OrderbookAggregatorServer<MyOrderbookAggregator>>()
+        .await;
+    tokio::spawn(async move {
+        let mut serving = false;
+        let mut interval = tokio::time::interval(HEALTH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let fresh = registry.read().unwrap().fresh(stale_after);
+            if fresh == serving {
+                continue;
+            }
+            serving = fresh;
+            let status = if fresh {
+                ServingStatus::Serving
+            } else {
+                ServingStatus::NotServing
+            };
+            info!("health {:?}", status);
+            reporter.set_service_status("", status).await;
+            if fresh {
+                reporter
+                    .set_serving::<OrderbookAggregatorServer<MyOrderbookAggregator>>()
+                    .await;
+            } else {
+                reporter
+                    .set_not_serving::<OrderbookAggregatorServer<MyOrderbookAggregator>>()
+                    .await;
+            }
+        }
+    });
+    service
+}
+
+pub fn reflection(
+) -> Result<ServerReflectionServer<impl ServerReflection>, tonic_reflection::server::Error> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(orderbook::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()
+}
@@ -0,0 +1,156 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::book::Exchange;
+use crate::metrics::METRICS;
+use crate::orderbook::{ConnectorState, ExchangeStatus};
+use log::info;
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct StatusReporter {
+    exchange: Exchange,
+    pair: String,
+    // status changes are rare and none may be lost, so the queue is unbounded
+    tx: UnboundedSender<ExchangeStatus>,
+}
+
+impl StatusReporter {
+    pub fn new(
+        exchange: Exchange,
+        pair: String,
+        tx: UnboundedSender<ExchangeStatus>,
+    ) -> StatusReporter {
+        StatusReporter { exchange, pair, tx }
+    }
+
+    pub fn report(&self, state: ConnectorState, detail: &str) {
+        info!("{} {} {:?} {}", self.exchange, self.pair, state, detail);
+        if state == ConnectorState::Reconnecting {
+            METRICS
+                .reconnects
+                .with_label_values(&[&self.exchange.to_string(), &self.pair])
+                .inc();
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let _ = self.tx.send(ExchangeStatus {
+            exchange: self.exchange.to_string(),
+            state: state as i32,
+            detail: detail.to_string(),
+            timestamp,
+            pair: self.pair.clone(),
+        });
+    }
+}
+
+// exponential delay between reconnections, reset once subscribed
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new()
+    }
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { delay: MIN_BACKOFF }
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = MIN_BACKOFF;
+    }
+
+    pub fn delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = cmp::min(self.delay * 2, MAX_BACKOFF);
+        delay
+    }
+}
+
+// asks a connector to close its websocket and clear its levels
+#[derive(Clone, Default)]
+pub struct StopSignal {
+    inner: Arc<(AtomicBool, Notify)>,
+}
+
+impl StopSignal {
+    pub fn new() -> StopSignal {
+        StopSignal::default()
+    }
+
+    pub fn stop(&self) {
+        self.inner.0.store(true, Ordering::SeqCst);
+        self.inner.1.notify_one();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.inner.0.load(Ordering::SeqCst)
+    }
+
+    pub async fn stopped(&self) {
+        while !self.is_stopped() {
+            self.inner.1.notified().await;
+        }
+    }
+
+    // sleep unless stopped in the meantime
+    pub async fn sleep(&self, delay: Duration) {
+        if !self.is_stopped() {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.inner.1.notified() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.delay(), Duration::from_secs(1));
+        assert_eq!(backoff.delay(), Duration::from_secs(2));
+        for _ in 0..10 {
+            backoff.delay();
+        }
+        assert_eq!(backoff.delay(), MAX_BACKOFF);
+        backoff.reset();
+        assert_eq!(backoff.delay(), MIN_BACKOFF);
+    }
+}
@@ -0,0 +1,332 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::book::{Exchange, Update};
+use crate::ingress::{ingress, Ingress, Overflow};
+use crate::metrics::METRICS;
+use crate::orderbook::ConnectorState;
+use crate::status::{Backoff, StatusReporter, StopSignal};
+use log::{error, info};
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+
+// a connector failing more often than this is given up
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+// runs one connector until stopped, restarting it when it panics or exits
+// on its own
+pub struct Supervisor {
+    exchange: Exchange,
+    pair: String,
+    queue: usize,
+    overflow: Overflow,
+    orders_tx: Sender<Update>,
+    // precisions of the levels the exchange left in the book
+    precisions: Arc<RwLock<BTreeMap<String, (u64, u64)>>>,
+    status: StatusReporter,
+    stop: StopSignal,
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl Supervisor {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        exchange: Exchange,
+        pair: String,
+        queue: usize,
+        overflow: Overflow,
+        orders_tx: Sender<Update>,
+        precisions: Arc<RwLock<BTreeMap<String, (u64, u64)>>>,
+        status: StatusReporter,
+        stop: StopSignal,
+    ) -> Supervisor {
+        Supervisor {
+            exchange,
+            pair,
+            queue,
+            overflow,
+            orders_tx,
+            precisions,
+            status,
+            stop,
+            max_restarts: MAX_RESTARTS,
+            window: RESTART_WINDOW,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    // start spawns a fresh connector feeding the given ingress queue
+    pub async fn run<F>(mut self, mut start: F)
+    where
+        F: FnMut(Ingress) -> JoinHandle<()>,
+    {
+        let labels = [self.exchange.to_string(), self.pair.clone()];
+        let up = METRICS
+            .connector_up
+            .with_label_values(&[&labels[0], &labels[1]]);
+        let restarts = METRICS
+            .connector_restarts
+            .with_label_values(&[&labels[0], &labels[1]]);
+        let mut backoff = Backoff::new();
+        loop {
+            // the forwarder ends once the connector and its last update are gone
+            let (tx, forwarder) = ingress(
+                self.exchange.clone(),
+                &self.pair,
+                self.queue,
+                self.overflow,
+                self.orders_tx.clone(),
+            );
+            up.set(1);
+            let started = tokio::time::Instant::now();
+            let result = start(tx).await;
+            up.set(0);
+            let _ = forwarder.await;
+            if self.stop.is_stopped() || self.orders_tx.is_closed() {
+                return;
+            }
+            let (cause, reason) = match result {
+                Ok(()) => ("exit", "exited".to_string()),
+                Err(e) if e.is_panic() => {
+                    ("panic", format!("panicked: {}", message(e.into_panic())))
+                }
+                Err(e) => ("exit", e.to_string()),
+            };
+            error!("{} {} connector {}", self.exchange, self.pair, reason);
+            METRICS
+                .connector_failures
+                .with_label_values(&[&labels[0], &labels[1], cause])
+                .inc();
+            self.clear().await;
+            self.status.report(ConnectorState::Failed, &reason);
+            if !self.may_restart(Instant::now()) {
+                let detail = format!(
+                    "gave up after {} restarts within {}s",
+                    self.max_restarts,
+                    self.window.as_secs()
+                );
+                error!("{} {} connector {}", self.exchange, self.pair, detail);
+                self.status.report(ConnectorState::Failed, &detail);
+                return;
+            }
+            // a connector that ran longer than the window failed on its own
+            if started.elapsed() >= self.window {
+                backoff.reset();
+            }
+            let delay = backoff.delay();
+            self.status.report(
+                ConnectorState::Reconnecting,
+                &format!("restart in {}s", delay.as_secs()),
+            );
+            self.stop.sleep(delay).await;
+            if self.stop.is_stopped() {
+                return;
+            }
+            info!("restart {} {}", self.exchange, self.pair);
+            restarts.inc();
+        }
+    }
+
+    // remove the levels a failed connector had no chance to clear
+    async fn clear(&self) {
+        let precisions = self
+            .precisions
+            .read()
+            .unwrap()
+            .get(&self.exchange.to_string())
+            .copied();
+        // without precisions the exchange never reached the book
+        if let Some((price, amount)) = precisions {
+            let _ = self
+                .orders_tx
+                .send(Update::new(self.exchange.clone(), price, amount))
+                .await;
+        }
+    }
+
+    // records a restart unless max_restarts happened within the window
+    fn may_restart(&mut self, now: Instant) -> bool {
+        while let Some(first) = self.restarts.front() {
+            if now.duration_since(*first) < self.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+fn message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::mpsc;
+
+    fn supervisor(
+        orders_tx: Sender<Update>,
+        status: StatusReporter,
+        stop: StopSignal,
+    ) -> Supervisor {
+        let precisions = Arc::new(RwLock::new(BTreeMap::from([(
+            Exchange::Binance.to_string(),
+            (8, 8),
+        )])));
+        let pair = "supervisor".to_string();
+        Supervisor::new(
+            Exchange::Binance,
+            pair,
+            4,
+            Overflow::Conflate,
+            orders_tx,
+            precisions,
+            status,
+            stop,
+        )
+    }
+
+    #[test]
+    fn test_may_restart() {
+        let (orders_tx, _orders_rx) = mpsc::channel(1);
+        let (events_tx, _events_rx) = mpsc::unbounded_channel();
+        let status = StatusReporter::new(Exchange::Binance, "supervisor".into(), events_tx);
+        let mut supervisor = supervisor(orders_tx, status, StopSignal::new());
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        for i in 0..MAX_RESTARTS as u32 {
+            assert!(supervisor.may_restart(start + second * i));
+        }
+        assert!(!supervisor.may_restart(start + RESTART_WINDOW / 2));
+        // the first restart left the window
+        assert!(supervisor.may_restart(start + RESTART_WINDOW));
+        assert!(!supervisor.may_restart(start + RESTART_WINDOW));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_panic() {
+        let (orders_tx, mut orders_rx) = mpsc::channel(16);
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let status = StatusReporter::new(Exchange::Binance, "supervisor".into(), events_tx);
+        let mut supervisor = supervisor(orders_tx, status, StopSignal::new());
+        supervisor.max_restarts = 2;
+        let starts = Arc::new(AtomicUsize::new(0));
+        let started = starts.clone();
+        supervisor
+            .run(move |tx| {
+                started.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut update = Update::new(Exchange::Binance, 8, 8);
+                    update.add_bid("1.0", "1.0");
+                    tx.send(update).await;
+                    panic!("boom");
+                })
+            })
+            .await;
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        // every update of a failed connector is followed by a clearing one
+        for _ in 0..3 {
+            assert!(!orders_rx.recv().await.unwrap().is_empty());
+            assert!(orders_rx.recv().await.unwrap().is_empty());
+        }
+        let mut events = Vec::new();
+        while let Ok(event) = events_rx.try_recv() {
+            events.push((event.state(), event.detail));
+        }
+        assert_eq!(events[0], (ConnectorState::Failed, "panicked: boom".into()));
+        assert_eq!(events[1].0, ConnectorState::Reconnecting);
+        assert_eq!(
+            events.last().unwrap(),
+            &(
+                ConnectorState::Failed,
+                "gave up after 2 restarts within 60s".into()
+            )
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_reset() {
+        let (orders_tx, _orders_rx) = mpsc::channel(16);
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let status = StatusReporter::new(Exchange::Binance, "supervisor".into(), events_tx);
+        let mut supervisor = supervisor(orders_tx, status, StopSignal::new());
+        supervisor.max_restarts = 3;
+        let starts = Arc::new(AtomicUsize::new(0));
+        let started = starts.clone();
+        supervisor
+            .run(move |_tx| {
+                // the first two fail right away, the third after a long run
+                let start = started.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    if start == 2 {
+                        tokio::time::sleep(RESTART_WINDOW).await;
+                    }
+                    panic!("boom");
+                })
+            })
+            .await;
+        let mut delays = Vec::new();
+        while let Ok(event) = events_rx.try_recv() {
+            if event.state() == ConnectorState::Reconnecting {
+                delays.push(event.detail);
+            }
+        }
+        assert_eq!(delays, ["restart in 1s", "restart in 2s", "restart in 1s"]);
+    }
+
+    #[tokio::test]
+    async fn test_stop() {
+        let (orders_tx, _orders_rx) = mpsc::channel(16);
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let status = StatusReporter::new(Exchange::Binance, "supervisor".into(), events_tx);
+        let stop = StopSignal::new();
+        let supervisor = supervisor(orders_tx, status, stop.clone());
+        let connector_stop = stop.clone();
+        let handle = tokio::spawn(supervisor.run(move |_tx| {
+            let stop = connector_stop.clone();
+            tokio::spawn(async move { stop.stopped().await })
+        }));
+        stop.stop();
+        handle.await.unwrap();
+        // a stopped connector is not a failure
+        assert!(events_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,303 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use crate::config::TlsConfig;
+use crate::metrics::METRICS;
+use log::{debug, warn};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+
+// a client that connects and stays silent must not hold its task forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// server tls whose certificates can be swapped while serving
+#[derive(Clone)]
+pub struct ServerTls {
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl ServerTls {
+    pub fn new(tls: &TlsConfig) -> Result<ServerTls, String> {
+        Ok(ServerTls {
+            current: Arc::new(RwLock::new(server_config(tls)?)),
+        })
+    }
+
+    // new connections use the new files, open ones keep their session
+    pub fn reload(&self, tls: &TlsConfig) -> Result<(), String> {
+        *self.current.write().unwrap() = server_config(tls)?;
+        Ok(())
+    }
+
+    // tls connections accepted on the listener, handshakes run concurrently
+    pub fn incoming(
+        &self,
+        listener: TcpListener,
+    ) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let current = self.current.clone();
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let acceptor = TlsAcceptor::from(current.read().unwrap().clone());
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(tls)) => {
+                            let _ = tx.send(Ok(tls)).await;
+                        }
+                        Ok(Err(e)) => {
+                            debug!("tls handshake with {} failed: {}", addr, e);
+                            METRICS
+                                .tls_handshake_failures
+                                .with_label_values(&["error"])
+                                .inc();
+                        }
+                        Err(_) => {
+                            warn!("tls handshake with {} timed out", addr);
+                            METRICS
+                                .tls_handshake_failures
+                                .with_label_values(&["timeout"])
+                                .inc();
+                        }
+                    }
+                });
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let certs = certificates(&tls.cert)?;
+    let key = private_key(&tls.key)?;
+    let builder = ServerConfig::builder();
+    let builder = match &tls.client_ca {
+        // only clients with a certificate signed by the ca get in
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certificates(ca)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("invalid client ca {}: {}", ca.display(), e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| format!("invalid client ca {}: {}", ca.display(), e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid tls certificate {}: {}", tls.cert.display(), e))?;
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid pem {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid pem {}: {}", path.display(), e))?
+        .ok_or_else(|| format!("no private key in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use std::fs;
+    use std::path::PathBuf;
+    use tonic::transport::{Channel, ClientTlsConfig, Identity, Server};
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+
+    struct Pki {
+        dir: PathBuf,
+        ca: rcgen::Certificate,
+    }
+
+    impl Pki {
+        fn new(dir: &Path) -> Pki {
+            fs::create_dir_all(dir).unwrap();
+            let mut params = CertificateParams::new(vec![]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(params).unwrap();
+            fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+            Pki {
+                dir: dir.to_path_buf(),
+                ca,
+            }
+        }
+
+        // certificate and key signed by the ca, written as <name>.pem/.key
+        fn issue(&self, name: &str) -> (String, String) {
+            let cert =
+                rcgen::Certificate::from_params(CertificateParams::new(vec![name.to_string()]))
+                    .unwrap();
+            let pem = (
+                cert.serialize_pem_with_signer(&self.ca).unwrap(),
+                cert.serialize_private_key_pem(),
+            );
+            fs::write(self.dir.join(format!("{}.pem", name)), &pem.0).unwrap();
+            fs::write(self.dir.join(format!("{}.key", name)), &pem.1).unwrap();
+            pem
+        }
+
+        fn server_config(&self) -> TlsConfig {
+            self.issue("localhost");
+            TlsConfig {
+                cert: self.dir.join("localhost.pem"),
+                key: self.dir.join("localhost.key"),
+                client_ca: Some(self.dir.join("ca.pem")),
+            }
+        }
+
+        fn ca(&self) -> String {
+            self.ca.serialize_pem().unwrap()
+        }
+    }
+
+    async fn check(addr: &str, tls: ClientTlsConfig) -> bool {
+        let Ok(endpoint) = Channel::from_shared(addr.to_string())
+            .unwrap()
+            .tls_config(tls.domain_name("localhost"))
+        else {
+            return false;
+        };
+        let Ok(channel) = endpoint.connect().await else {
+            return false;
+        };
+        HealthClient::new(channel)
+            .check(HealthCheckRequest::default())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_reload() {
+        let dir = std::env::temp_dir().join(format!("infonode-tls-{}", std::process::id()));
+        let first = Pki::new(&dir.join("first"));
+        let server_tls = ServerTls::new(&first.server_config()).unwrap();
+        let (client_cert, client_key) = first.issue("client");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("https://{}", listener.local_addr().unwrap());
+        let (_reporter, health) = tonic_health::server::health_reporter();
+        tokio::spawn(
+            Server::builder()
+                .add_service(health)
+                .serve_with_incoming(server_tls.incoming(listener)),
+        );
+
+        let identity = Identity::from_pem(&client_cert, &client_key);
+        let trusted = ClientTlsConfig::new()
+            .ca_certificate(tonic::transport::Certificate::from_pem(first.ca()));
+        assert!(check(&addr, trusted.clone().identity(identity.clone())).await);
+        // no client certificate, no service
+        assert!(!check(&addr, trusted).await);
+
+        // a new ca replaces the old one for the next connections
+        let second = Pki::new(&dir.join("second"));
+        server_tls.reload(&second.server_config()).unwrap();
+        let (cert, key) = second.issue("client");
+        let renewed = ClientTlsConfig::new()
+            .ca_certificate(tonic::transport::Certificate::from_pem(second.ca()))
+            .identity(Identity::from_pem(cert, key));
+        assert!(check(&addr, renewed).await);
+        let stale = ClientTlsConfig::new()
+            .ca_certificate(tonic::transport::Certificate::from_pem(first.ca()))
+            .identity(identity);
+        assert!(!check(&addr, stale).await);
+
+        // broken files keep the current certificates
+        fs::write(dir.join("second").join("localhost.key"), "").unwrap();
+        assert!(server_tls
+            .reload(&TlsConfig {
+                cert: dir.join("second").join("localhost.pem"),
+                key: dir.join("second").join("localhost.key"),
+                client_ca: None,
+            })
+            .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let dir = std::env::temp_dir().join(format!("infonode-tls-timeout-{}", std::process::id()));
+        let pki = Pki::new(&dir);
+        let server_tls = ServerTls::new(&pki.server_config()).unwrap();
+        let (client_cert, client_key) = pki.issue("client");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let (_reporter, health) = tonic_health::server::health_reporter();
+        tokio::spawn(
+            Server::builder()
+                .add_service(health)
+                .serve_with_incoming(server_tls.incoming(listener)),
+        );
+        let timeouts = METRICS
+            .tls_handshake_failures
+            .with_label_values(&["timeout"]);
+        let before = timeouts.get();
+
+        // a silent client doesn't hold up the next one
+        let _silent = TcpStream::connect(local).await.unwrap();
+        let tls = ClientTlsConfig::new()
+            .ca_certificate(tonic::transport::Certificate::from_pem(pki.ca()))
+            .identity(Identity::from_pem(client_cert, client_key));
+        assert!(check(&format!("https://{}", local), tls).await);
+
+        tokio::time::pause();
+        tokio::time::sleep(HANDSHAKE_TIMEOUT).await;
+        tokio::task::yield_now().await;
+        assert_eq!(timeouts.get(), before + 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+// the fixture of the integration tests: pipelines fed by mock exchanges,
+// served on ephemeral ports, and timed reads of their streams
+use infonode::auth::Authenticator;
+use infonode::config::Config;
+use infonode::mock::MockExchange;
+use infonode::orderbook::orderbook_aggregator_server::OrderbookAggregatorServer;
+use infonode::pipeline::Pipelines;
+use infonode::service::{health, reflection, MyOrderbookAggregator};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Channel, Server};
+use tonic::{Status, Streaming};
+use tonic_web::GrpcWebLayer;
+use tower::util::option_layer;
+
+// point the enabled exchanges at the mocks
+pub fn mock_config(mocks: &[MockExchange]) -> Config {
+    let mut config = Config {
+        pairs: vec!["ethbtc".to_string()],
+        ..Config::default()
+    };
+    config.exchanges.binance.enabled = false;
+    config.exchanges.bitstamp.enabled = false;
+    for mock in mocks {
+        let endpoints = mock.endpoints();
+        let exchange = config.exchanges.get_mut(&mock.exchange());
+        exchange.enabled = true;
+        exchange.rest = Some(endpoints.rest);
+        exchange.websocket = Some(endpoints.websocket);
+    }
+    config
+}
+
+// start the pipelines and serve grpc on an ephemeral port
+pub async fn serve(config: Config) -> (Pipelines, Channel) {
+    let (pipelines, addr) = listen(config).await;
+    let channel = Channel::from_shared(format!("http://{}", addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    (pipelines, channel)
+}
+
+pub async fn listen(config: Config) -> (Pipelines, SocketAddr) {
+    let stale_after = Duration::from_secs(config.stale_after);
+    let pipelines = Pipelines::start(config.clone()).unwrap();
+    let aggregator = MyOrderbookAggregator::new(pipelines.registry());
+    let health = health(pipelines.registry(), stale_after).await;
+    let authenticator = Authenticator::new(&config.auth);
+
+    let cors = config.grpc_web.as_ref().map(|c| c.cors().unwrap());
+    let grpc_web = cors.is_some();
+
+    let (listener, addr) = bind().await;
+    tokio::spawn(
+        Server::builder()
+            .accept_http1(grpc_web)
+            .layer(option_layer(cors))
+            .layer(option_layer(grpc_web.then(GrpcWebLayer::new)))
+            .add_service(health)
+            .add_service(reflection().unwrap())
+            .add_service(OrderbookAggregatorServer::with_interceptor(
+                aggregator,
+                authenticator,
+            ))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    (pipelines, addr)
+}
+
+pub async fn bind() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+// the next message of a stream, which has 10s to come
+pub async fn next<T>(stream: &mut Streaming<T>) -> Result<Option<T>, Status> {
+    tokio::time::timeout(Duration::from_secs(10), stream.message())
+        .await
+        .expect("no message within 10s")
+}
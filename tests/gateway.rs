@@ -0,0 +1,128 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use common::{bind, mock_config};
+use futures::{SinkExt, StreamExt};
+use infonode::auth::Authenticator;
+use infonode::book::Exchange;
+use infonode::gateway;
+use infonode::mock::{binance_depth, MockExchange, Step};
+use infonode::pipeline::Pipelines;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tonic::Status;
+
+// each test binary uses part of the fixture
+#[allow(dead_code)]
+mod common;
+
+// send a message unless empty, then wait for the next reply
+async fn call(
+    socket: &mut WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+    msg: &str,
+) -> json::JsonValue {
+    if !msg.is_empty() {
+        socket.send(WsMessage::Text(msg.to_string())).await.unwrap();
+    }
+    let reply = tokio::time::timeout(Duration::from_secs(10), socket.next())
+        .await
+        .expect("no reply")
+        .unwrap()
+        .unwrap();
+    json::parse(reply.to_text().unwrap()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_gateway() {
+    let binance = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (10, 10),
+        vec![vec![Step::Repeat(
+            binance_depth(&[("0.25", "1"), ("0.125", "1")], &[("0.5", "2")]),
+            Duration::from_millis(50),
+        )]],
+    );
+    let mut pipelines = Pipelines::start(mock_config(&[binance])).unwrap();
+    let (listener, addr) = bind().await;
+    let (stop, stopped) = watch::channel(());
+    let server = tokio::spawn(gateway::serve(
+        listener,
+        pipelines.registry(),
+        Authenticator::new(&Default::default()),
+        stopped,
+    ));
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+        .await
+        .unwrap();
+    let reply = call(
+        &mut socket,
+        r#"{"op":"subscribe","pair":"ethbtc","depth":1}"#,
+    )
+    .await;
+    assert_eq!(reply["type"], "subscribed");
+    assert_eq!(reply["pair"], "ethbtc");
+    let summary = loop {
+        let reply = call(&mut socket, "").await;
+        assert_eq!(reply["type"], "summary");
+        if !reply["bids"].is_empty() {
+            break reply;
+        }
+    };
+    assert_eq!(summary["pair"], "ethbtc");
+    assert_eq!(summary["bids"].len(), 1);
+    assert_eq!(summary["bids"][0]["price"], 0.25);
+    assert_eq!(summary["bids"][0]["exchange"], "binance");
+    assert_eq!(summary["spread"], 0.25);
+
+    let reply = call(&mut socket, r#"{"op":"subscribe","pair":"xrpusd"}"#).await;
+    assert_eq!(reply["type"], "error");
+    let reply = call(&mut socket, "{not json").await;
+    assert_eq!(reply["type"], "error");
+
+    // summaries already queued may arrive before the confirmation
+    let mut reply = call(&mut socket, r#"{"op":"unsubscribe","pair":"ethbtc"}"#).await;
+    while reply["type"] == "summary" {
+        reply = call(&mut socket, "").await;
+    }
+    assert_eq!(reply["type"], "unsubscribed");
+
+    // the shutdown closes the session and stops the gateway
+    stop.send(()).unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match socket.next().await {
+                Some(Ok(WsMessage::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => panic!("{}", e),
+            }
+        }
+    });
+    closed.await.expect("session not closed");
+    tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .expect("gateway not stopped")
+        .unwrap();
+    pipelines.close(Status::unavailable("done")).await;
+}
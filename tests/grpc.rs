@@ -0,0 +1,446 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use common::{mock_config, next, serve};
+use infonode::book::Exchange;
+use infonode::config::KeyConfig;
+use infonode::metrics;
+use infonode::mock::{binance_depth, bitstamp_depth, bitstamp_event, MockExchange, Step};
+use infonode::orderbook::orderbook_aggregator_client::OrderbookAggregatorClient;
+use infonode::orderbook::{ConnectorState, Empty, SummaryRequest};
+use std::time::Duration;
+use tonic::{Request, Status};
+
+// each test binary uses part of the fixture
+#[allow(dead_code)]
+mod common;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_end_to_end() {
+    let binance = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (10, 10),
+        vec![vec![
+            Step::Send("{not json".to_string()),
+            Step::Send(binance_depth(&[("abc", "1")], &[])),
+            Step::Send(binance_depth(&[("0.25", "1")], &[("0.75", "2")])),
+            Step::Disconnect,
+        ]],
+    );
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        vec![vec![
+            Step::Subscribe,
+            Step::Send(bitstamp_depth("ethbtc", &[("0.5", "3")], &[("1.5", "4")])),
+            Step::Disconnect,
+        ]],
+    );
+
+    let mut config = mock_config(&[binance, bitstamp]);
+    config.summary_latency = true;
+    let (_pipelines, channel) = serve(config).await;
+    let mut client = OrderbookAggregatorClient::new(channel);
+    let mut stream = client
+        .book_summary(Request::new(SummaryRequest::default()))
+        .await
+        .unwrap()
+        .into_inner();
+
+    // wait for both exchanges to land in the aggregated book
+    loop {
+        let summary = next(&mut stream).await.unwrap().unwrap();
+        if summary.bids.len() == 2 {
+            assert_eq!(summary.bids[0].exchange, "bitstamp");
+            assert_eq!(summary.bids[0].price, 0.5);
+            assert_eq!(summary.bids[1].exchange, "binance");
+            assert_eq!(summary.bids[1].price, 0.25);
+            assert_eq!(summary.asks[0].exchange, "binance");
+            assert_eq!(summary.asks[0].amount, 2.0);
+            assert_eq!(summary.exchanges.len(), 2);
+            assert_eq!(summary.pair, "ethbtc");
+            // only bitstamp stamps its events
+            let latency = summary.latency.unwrap();
+            assert_eq!(latency.exchange_us > 0, latency.exchange == "bitstamp");
+            assert!(metrics::METRICS.encode().contains(r#"stage="parse"#));
+            break;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_exchange_events() {
+    let session = || {
+        vec![
+            Step::Subscribe,
+            Step::Send(bitstamp_depth("ethbtc", &[("0.5", "3")], &[])),
+            Step::Disconnect,
+        ]
+    };
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        vec![session(), session()],
+    );
+
+    let (_pipelines, channel) = serve(mock_config(&[bitstamp])).await;
+    let mut client = OrderbookAggregatorClient::new(channel);
+    let mut stream = client
+        .exchange_events(Request::new(Empty {}))
+        .await
+        .unwrap()
+        .into_inner();
+
+    // subscribed, dropped and subscribed again after the backoff
+    let mut states = Vec::new();
+    while states
+        .iter()
+        .filter(|s| **s == ConnectorState::Subscribed)
+        .count()
+        < 2
+    {
+        let event = next(&mut stream).await.unwrap().unwrap();
+        assert_eq!(event.exchange, "bitstamp");
+        assert_eq!(event.pair, "ethbtc");
+        states.push(event.state());
+    }
+    let first = states
+        .iter()
+        .position(|s| *s == ConnectorState::Subscribed)
+        .unwrap();
+    assert_eq!(
+        states[first + 1..first + 4],
+        [
+            ConnectorState::Failed,
+            ConnectorState::Reconnecting,
+            ConnectorState::Connecting
+        ]
+    );
+
+    let summary = client
+        .book_summary(Request::new(SummaryRequest::default()))
+        .await
+        .unwrap()
+        .into_inner()
+        .message()
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(summary.exchanges.len(), 1);
+    assert_eq!(summary.exchanges[0].exchange, "bitstamp");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_bitstamp_protocol() {
+    let refused = bitstamp_event(
+        "bts:error",
+        json::object! { code: json::Null, message: "Bad subscription string." },
+    );
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        vec![
+            vec![Step::Send(refused)],
+            vec![
+                Step::Subscribe,
+                Step::Send(bitstamp_depth("ethbtc", &[("0.5", "3")], &[])),
+                Step::Send(bitstamp_event("bts:request_reconnect", json::object! {})),
+            ],
+            vec![
+                Step::Subscribe,
+                Step::Send(bitstamp_depth("ethbtc", &[("0.75", "3")], &[])),
+                Step::Send(bitstamp_event("bts:request_reconnect", json::object! {})),
+            ],
+            vec![
+                Step::Subscribe,
+                Step::Send(bitstamp_depth("ethbtc", &[("0.875", "3")], &[])),
+            ],
+        ],
+    );
+
+    let (_pipelines, channel) = serve(mock_config(&[bitstamp])).await;
+    let mut client = OrderbookAggregatorClient::new(channel);
+    let mut stream = client
+        .exchange_events(Request::new(Empty {}))
+        .await
+        .unwrap()
+        .into_inner();
+
+    // refused, retried after the backoff, then reconnected on request
+    // right away, and after a backoff when asked again so soon
+    let mut events = Vec::new();
+    while events
+        .iter()
+        .filter(|(state, _)| *state == ConnectorState::Subscribed)
+        .count()
+        < 3
+    {
+        let event = next(&mut stream).await.unwrap().unwrap();
+        events.push((event.state(), event.detail));
+    }
+    assert!(events.contains(&(
+        ConnectorState::Failed,
+        "subscription failed: Bad subscription string.".to_string()
+    )));
+    let first = events
+        .iter()
+        .position(|(state, _)| *state == ConnectorState::Subscribed)
+        .unwrap();
+    assert_eq!(
+        events[first + 1],
+        (
+            ConnectorState::Reconnecting,
+            "requested by bitstamp".to_string()
+        )
+    );
+    assert_eq!(events[first + 2].0, ConnectorState::PrecisionsFetched);
+    assert_eq!(events[first + 3].0, ConnectorState::Subscribed);
+    assert_eq!(
+        events[first + 4],
+        (
+            ConnectorState::Reconnecting,
+            "requested by bitstamp again, retry in 1s".to_string()
+        )
+    );
+    assert_eq!(events[first + 5].0, ConnectorState::Connecting);
+
+    let mut summaries = client
+        .book_summary(Request::new(SummaryRequest::default()))
+        .await
+        .unwrap()
+        .into_inner();
+    loop {
+        let summary = next(&mut summaries).await.unwrap().unwrap();
+        if summary.bids.first().map(|bid| bid.price) == Some(0.875) {
+            break;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_reload() {
+    let interval = Duration::from_millis(20);
+    let binance = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (10, 10),
+        vec![vec![Step::Repeat(
+            binance_depth(&[("0.25", "1")], &[]),
+            interval,
+        )]],
+    );
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        vec![vec![
+            Step::Subscribe,
+            Step::Repeat(bitstamp_depth("ethbtc", &[("0.5", "3")], &[]), interval),
+        ]],
+    );
+    let mut config = mock_config(&[binance, bitstamp]);
+    config.exchanges.bitstamp.enabled = false;
+
+    let (mut pipelines, channel) = serve(config.clone()).await;
+    let mut client = OrderbookAggregatorClient::new(channel);
+    let mut stream = client
+        .book_summary(Request::new(SummaryRequest::default()))
+        .await
+        .unwrap()
+        .into_inner();
+    while next(&mut stream).await.unwrap().unwrap().bids.is_empty() {}
+
+    // enable bitstamp and keep only the best level
+    config.exchanges.bitstamp.enabled = true;
+    config.depth = 1;
+    pipelines.reload(config.clone());
+    loop {
+        let summary = next(&mut stream).await.unwrap().unwrap();
+        if summary.bids[0].exchange == "bitstamp" {
+            assert_eq!(summary.bids.len(), 1);
+            break;
+        }
+    }
+
+    // binance leaves no levels behind
+    config.exchanges.binance.enabled = false;
+    config.depth = 20;
+    pipelines.reload(config.clone());
+    loop {
+        let summary = next(&mut stream).await.unwrap().unwrap();
+        let stopped = summary.exchanges.iter().any(|status| {
+            status.exchange == "binance" && status.state() == ConnectorState::Disconnected
+        });
+        if stopped && summary.bids.iter().all(|bid| bid.exchange == "bitstamp") {
+            break;
+        }
+    }
+
+    // streams of a removed pair end with an error
+    config.pairs = vec!["btcusd".to_string()];
+    pipelines.reload(config);
+    let status = loop {
+        if let Err(status) = next(&mut stream).await {
+            break status;
+        }
+    };
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    pipelines.close(Status::unavailable("test done")).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_shutdown() {
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        // one book, then a quiet socket the stop must not wait on
+        vec![vec![
+            Step::Subscribe,
+            Step::Send(bitstamp_depth("ethbtc", &[("0.5", "3")], &[])),
+        ]],
+    );
+    let (mut pipelines, channel) = serve(mock_config(&[bitstamp])).await;
+    let mut client = OrderbookAggregatorClient::new(channel);
+    let mut stream = client
+        .book_summary(Request::new(SummaryRequest::default()))
+        .await
+        .unwrap()
+        .into_inner();
+    let mut events = client
+        .exchange_events(Request::new(Empty {}))
+        .await
+        .unwrap()
+        .into_inner();
+    while next(&mut stream).await.unwrap().unwrap().bids.is_empty() {}
+
+    tokio::time::timeout(
+        Duration::from_secs(2),
+        pipelines.close(Status::unavailable("server shutting down")),
+    )
+    .await
+    .expect("connectors not closed");
+
+    // a last summary with the book, then the status
+    let mut last = None;
+    let status = loop {
+        match next(&mut stream).await {
+            Ok(Some(summary)) => last = Some(summary),
+            Ok(None) => panic!("summaries ended without a status"),
+            Err(status) => break status,
+        }
+    };
+    assert!(last.is_some(), "no summary before {:?}", status);
+    assert_eq!(last.unwrap().bids[0].exchange, "bitstamp");
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    assert_eq!(status.message(), "server shutting down");
+    let status = loop {
+        match next(&mut events).await {
+            Ok(event) => assert!(event.is_some(), "events ended without a status"),
+            Err(status) => break status,
+        }
+    };
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+
+    let refused = client
+        .book_summary(Request::new(SummaryRequest::default()))
+        .await
+        .unwrap_err();
+    assert_eq!(refused.code(), tonic::Code::Unavailable);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_entitlements() {
+    let binance = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (10, 10),
+        vec![vec![
+            Step::Send(binance_depth(&[("0.25", "1"), ("0.125", "1")], &[])),
+            Step::Disconnect,
+        ]],
+    );
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        vec![vec![
+            Step::Subscribe,
+            Step::Send(bitstamp_depth("ethbtc", &[("0.5", "3")], &[])),
+            Step::Disconnect,
+        ]],
+    );
+    let mut config = mock_config(&[binance, bitstamp]);
+    config.auth.keys = vec![
+        KeyConfig {
+            name: "reader".to_string(),
+            key: "reader-key".to_string(),
+            pairs: vec!["ethbtc".to_string()],
+            exchanges: vec![Exchange::Binance],
+            max_depth: Some(1),
+            max_rate: None,
+        },
+        KeyConfig {
+            name: "other".to_string(),
+            key: "other-key".to_string(),
+            pairs: vec!["btcusdt".to_string()],
+            exchanges: vec![],
+            max_depth: None,
+            max_rate: None,
+        },
+    ];
+    let (_pipelines, channel) = serve(config).await;
+    let mut client = OrderbookAggregatorClient::new(channel);
+    let request = |key: &str| {
+        let mut request = Request::new(SummaryRequest::default());
+        if !key.is_empty() {
+            let value = format!("Bearer {}", key).parse().unwrap();
+            request.metadata_mut().insert("authorization", value);
+        }
+        request
+    };
+
+    let status = client.book_summary(request("")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    let status = client.book_summary(request("other-key")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    let mut stream = client
+        .book_summary(request("reader-key"))
+        .await
+        .unwrap()
+        .into_inner();
+    loop {
+        let summary = next(&mut stream).await.unwrap().unwrap();
+        assert!(summary.bids.len() <= 1);
+        assert!(summary.bids.iter().all(|bid| bid.exchange == "binance"));
+        assert!(summary.exchanges.iter().all(|s| s.exchange == "binance"));
+        if !summary.bids.is_empty() {
+            assert_eq!(summary.bids[0].price, 0.25);
+            break;
+        }
+    }
+}
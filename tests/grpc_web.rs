@@ -0,0 +1,114 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use common::{listen, mock_config};
+use infonode::book::Exchange;
+use infonode::config::GrpcWebConfig;
+use infonode::mock::{binance_depth, MockExchange, Step};
+use infonode::orderbook::{Summary, SummaryRequest};
+use prost::Message;
+use std::time::Duration;
+use tonic::Status;
+
+// each test binary uses part of the fixture
+#[allow(dead_code)]
+mod common;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_grpc_web() {
+    let binance = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (10, 10),
+        vec![vec![Step::Repeat(
+            binance_depth(&[("0.25", "1")], &[("0.5", "2")]),
+            Duration::from_millis(50),
+        )]],
+    );
+    let mut config = mock_config(&[binance]);
+    config.grpc_web = Some(GrpcWebConfig {
+        allow_origins: vec!["http://dash.example".to_string()],
+    });
+    let (mut pipelines, addr) = listen(config).await;
+    let url = format!("http://{}/orderbook.OrderbookAggregator/BookSummary", addr);
+    let http = reqwest::Client::new();
+
+    // preflight from the dashboard and from anywhere else
+    let preflight = |origin: &'static str| {
+        http.request(reqwest::Method::OPTIONS, &url)
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "content-type,x-grpc-web")
+            .send()
+    };
+    let allowed = preflight("http://dash.example").await.unwrap();
+    assert_eq!(
+        allowed.headers()["access-control-allow-origin"],
+        "http://dash.example"
+    );
+    let refused = preflight("http://evil.example").await.unwrap();
+    assert!(!refused
+        .headers()
+        .contains_key("access-control-allow-origin"));
+
+    // length prefixed messages, 0x80 flags the trailers
+    let request = SummaryRequest {
+        pair: "ethbtc".to_string(),
+    }
+    .encode_to_vec();
+    let mut body = vec![0];
+    body.extend((request.len() as u32).to_be_bytes());
+    body.extend(request);
+    let mut response = http
+        .post(&url)
+        .header("origin", "http://dash.example")
+        .header("content-type", "application/grpc-web+proto")
+        .header("x-grpc-web", "1")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/grpc-web+proto"
+    );
+    let mut buf = Vec::new();
+    let summary = loop {
+        while buf.len() < 5 {
+            buf.extend(response.chunk().await.unwrap().expect("stream ended"));
+        }
+        let len = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+        while buf.len() < 5 + len {
+            buf.extend(response.chunk().await.unwrap().expect("stream ended"));
+        }
+        assert_eq!(buf[0], 0, "trailers before a summary");
+        let summary = Summary::decode(&buf[5..5 + len]).unwrap();
+        buf.drain(..5 + len);
+        if !summary.bids.is_empty() {
+            break summary;
+        }
+    };
+    assert_eq!(summary.bids[0].price, 0.25);
+    assert_eq!(summary.spread, 0.25);
+    pipelines.close(Status::unavailable("done")).await;
+}
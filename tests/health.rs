@@ -0,0 +1,79 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use common::{mock_config, serve};
+use infonode::book::Exchange;
+use infonode::mock::{bitstamp_depth, MockExchange, Step};
+use std::time::Duration;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::{health_check_response, HealthCheckRequest};
+
+// each test binary uses part of the fixture
+#[allow(dead_code)]
+mod common;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_health() {
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        vec![vec![
+            Step::Subscribe,
+            Step::Wait(Duration::from_millis(300)),
+            Step::Send(bitstamp_depth("ethbtc", &[("0.5", "3")], &[])),
+            Step::Wait(Duration::from_secs(3)),
+            Step::Disconnect,
+        ]],
+    );
+    let mut config = mock_config(&[bitstamp]);
+    config.stale_after = 1;
+    let (_pipelines, channel) = serve(config).await;
+    let mut health = HealthClient::new(channel);
+    let service = "orderbook.OrderbookAggregator".to_string();
+
+    // not serving, serving once the book arrives, stale again
+    let mut statuses = vec![];
+    let start = tokio::time::Instant::now();
+    while statuses.len() < 3 && start.elapsed() < Duration::from_secs(10) {
+        let status = health
+            .check(HealthCheckRequest {
+                service: service.clone(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .status();
+        if statuses.last() != Some(&status) {
+            statuses.push(status);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(
+        statuses,
+        [
+            health_check_response::ServingStatus::NotServing,
+            health_check_response::ServingStatus::Serving,
+            health_check_response::ServingStatus::NotServing
+        ]
+    );
+}
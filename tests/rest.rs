@@ -0,0 +1,128 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use common::{bind, mock_config};
+use infonode::auth::Authenticator;
+use infonode::book::Exchange;
+use infonode::mock::{binance_depth, bitstamp_depth, MockExchange, Step};
+use infonode::pipeline::Pipelines;
+use infonode::rest;
+use std::time::Duration;
+use tokio::sync::watch;
+use tonic::Status;
+
+// each test binary uses part of the fixture
+#[allow(dead_code)]
+mod common;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_rest() {
+    let binance = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (8, 6),
+        vec![vec![Step::Repeat(
+            binance_depth(&[("0.25", "1"), ("0.125", "1")], &[("0.75", "2")]),
+            Duration::from_millis(50),
+        )]],
+    );
+    let bitstamp = MockExchange::new(
+        Exchange::Bitstamp,
+        "ethbtc",
+        (10, 10),
+        vec![vec![
+            Step::Subscribe,
+            Step::Repeat(
+                bitstamp_depth("ethbtc", &[("0.5", "3")], &[("1.5", "4")]),
+                Duration::from_millis(50),
+            ),
+        ]],
+    );
+    let mut pipelines = Pipelines::start(mock_config(&[binance, bitstamp])).unwrap();
+    let (listener, addr) = bind().await;
+    let (_stop, stopped) = watch::channel(());
+    tokio::spawn(rest::serve(
+        listener,
+        pipelines.registry(),
+        Authenticator::default(),
+        Duration::from_secs(10),
+        stopped,
+    ));
+    let get = |path: &str| {
+        let url = format!("http://{}{}", addr, path);
+        async move {
+            let response = reqwest::get(url).await.unwrap();
+            let status = response.status();
+            (
+                status,
+                json::parse(&response.text().await.unwrap()).unwrap(),
+            )
+        }
+    };
+
+    // both exchanges in the book
+    let start = tokio::time::Instant::now();
+    let book = loop {
+        let (status, book) = get("/book/ethbtc?depth=1").await;
+        assert_eq!(status, 200);
+        if book["exchanges"].len() == 2 && book["bids"][0]["exchange"] == "bitstamp" {
+            break book;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "no book");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert_eq!(book["pair"], "ethbtc");
+    assert_eq!(book["bids"].len(), 1);
+    assert_eq!(book["bids"][0]["price"], 0.5);
+    assert_eq!(book["asks"].len(), 1);
+
+    let (_, book) = get("/book/ethbtc?exchanges=binance").await;
+    assert_eq!(book["bids"].len(), 2);
+    assert_eq!(book["bids"][0]["exchange"], "binance");
+    assert_eq!(book["spread"], 0.5);
+    assert_eq!(book["exchanges"].len(), 1);
+
+    let (status, book) = get("/book/ethbtc/?depth=1").await;
+    assert_eq!(status, 200);
+    assert_eq!(book["pair"], "ethbtc");
+
+    assert_eq!(get("/book/xrpusd").await.0, 404);
+    assert_eq!(get("/book/ethbtc?depth=0").await.0, 400);
+    assert_eq!(get("/book/ethbtc?exchanges=kraken").await.0, 400);
+    assert_eq!(get("/nothing").await.0, 404);
+
+    let (_, instruments) = get("/instruments").await;
+    let ethbtc = &instruments["instruments"][0];
+    assert_eq!(ethbtc["pair"], "ethbtc");
+    assert_eq!(ethbtc["default"], true);
+    assert_eq!(ethbtc["exchanges"][0]["exchange"], "binance");
+    assert_eq!(ethbtc["exchanges"][0]["price_precision"], 8);
+    assert_eq!(ethbtc["exchanges"][0]["amount_precision"], 6);
+    assert_eq!(ethbtc["exchanges"][1]["exchange"], "bitstamp");
+
+    let (_, status) = get("/status").await;
+    assert_eq!(status["healthy"], true);
+    assert_eq!(status["pairs"][0]["pair"], "ethbtc");
+    assert!(status["pairs"][0]["last_book"].as_u64().is_some());
+    assert_eq!(status["pairs"][0]["exchanges"].len(), 2);
+    pipelines.close(Status::unavailable("done")).await;
+}
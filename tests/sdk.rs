@@ -0,0 +1,81 @@
+/**
+ *  Copyright (c) 2023 Antonino Nolano. Licensed under the MIT license, as
+ * follows:
+ *
+ *  Permission is hereby granted, free of charge, to any person obtaining a copy
+ *  of this software and associated documentation files (the "Software"), to
+ * deal in the Software without restriction, including without limitation the
+ * rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+ * sell copies of the Software, and to permit persons to whom the Software is
+ *  furnished to do so, subject to the following conditions:
+ *
+ *  The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ *  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ *  IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ *  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ *  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ *  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+ * IN THE SOFTWARE.
+ */
+use bigdecimal::BigDecimal;
+use common::{listen, mock_config};
+use infonode::book::Exchange;
+use infonode::mock::{binance_depth, MockExchange, Step};
+use infonode::orderbook::ConnectorState;
+use infonode::sdk::{ClientConfig, Subscription};
+use std::str::FromStr;
+use std::time::Duration;
+use tonic::Status;
+
+// each test binary uses part of the fixture
+#[allow(dead_code)]
+mod common;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sdk() {
+    let binance = MockExchange::new(
+        Exchange::Binance,
+        "ethbtc",
+        (4, 3),
+        vec![vec![Step::Repeat(
+            binance_depth(&[("0.30001", "0.33333")], &[("0.5", "2")]),
+            Duration::from_millis(50),
+        )]],
+    );
+    let (mut pipelines, addr) = listen(mock_config(&[binance])).await;
+    let config = ClientConfig::new(&format!("http://{}", addr));
+
+    let mut client = config.connect().await.unwrap();
+    let mut subscription = Subscription::new(config.clone(), "");
+    let book = loop {
+        let book = subscription.next().await.unwrap();
+        if !book.bids.is_empty() {
+            break book.clone();
+        }
+    };
+    let dec = |s| BigDecimal::from_str(s).unwrap();
+    assert_eq!(book.pair, "ethbtc");
+    assert_eq!(book.best_bid().unwrap().price, dec("0.3000"));
+    assert_eq!(book.best_bid().unwrap().amount, dec("0.333"));
+    assert_eq!(book.spread(), Some(dec("0.2")));
+    assert_eq!(
+        book.exchanges["binance"].state(),
+        ConnectorState::Subscribed
+    );
+    let next = subscription.next().await.unwrap();
+    assert_eq!(next.sequence, book.sequence + 1);
+    assert_eq!(subscription.missed(), None);
+
+    let instruments = client.instruments().await.unwrap();
+    assert_eq!(instruments.len(), 1);
+    assert!(instruments[0].default);
+    assert_eq!(instruments[0].precisions[0].price, 4);
+
+    let mut unknown = Subscription::new(config, "xrpusd");
+    let status = unknown.next().await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+    pipelines.close(Status::unavailable("done")).await;
+}